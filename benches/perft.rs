@@ -0,0 +1,44 @@
+use std::time::Instant;
+
+use chess::BENCH_POSITIONS;
+
+fn main() {
+    let mut total_nodes = 0;
+    let mut total_secs = 0.0;
+    let mut mismatches = 0;
+    for (name, fen, depth, expected) in BENCH_POSITIONS.iter() {
+        let start = Instant::now();
+        let nodes = match chess::perft(fen, *depth) {
+            Ok(nodes) => nodes,
+            Err(e) => {
+                eprintln!("Error: {}: {}", name, e);
+                std::process::exit(1);
+            }
+        };
+        let secs = start.elapsed().as_secs_f64();
+        total_nodes += nodes;
+        total_secs += secs;
+        println!(
+            "{:<12} perft({}) {:>9} nodes {:>9.1} ms {:>12.0} nodes/s",
+            name,
+            depth,
+            nodes,
+            secs * 1000.0,
+            nodes as f64 / secs
+        );
+        if nodes != *expected {
+            eprintln!("  expected {} nodes, got {}", expected, nodes);
+            mismatches += 1;
+        }
+    }
+    println!(
+        "{:<12}          {:>9} nodes {:>9.1} ms {:>12.0} nodes/s",
+        "total",
+        total_nodes,
+        total_secs * 1000.0,
+        total_nodes as f64 / total_secs
+    );
+    if mismatches > 0 {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,7339 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+mod piece {
+    use super::{Board, Location, WalkStrategy};
+    use serde::Serialize;
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Type {
+        Pawn,
+        Bishop,
+        Knight,
+        Rook,
+        Queen,
+        King,
+    }
+
+    impl Type {
+        pub fn letter(self) -> char {
+            match self {
+                Type::Pawn => 'P',
+                Type::Bishop => 'B',
+                Type::Knight => 'N',
+                Type::Rook => 'R',
+                Type::Queen => 'Q',
+                Type::King => 'K',
+            }
+        }
+
+        pub fn from_letter(c: char) -> Option<Type> {
+            match c.to_ascii_uppercase() {
+                'P' => Some(Type::Pawn),
+                'B' => Some(Type::Bishop),
+                'N' => Some(Type::Knight),
+                'R' => Some(Type::Rook),
+                'Q' => Some(Type::Queen),
+                'K' => Some(Type::King),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Color {
+        White,
+        Black,
+    }
+
+    impl Color {
+        pub fn opposite(self) -> Color {
+            match self {
+                Color::White => Color::Black,
+                Color::Black => Color::White,
+            }
+        }
+
+        pub fn name(self) -> &'static str {
+            match self {
+                Color::White => "white",
+                Color::Black => "black",
+            }
+        }
+
+        // The rank step of this color's pawns
+        pub fn forward(self) -> i8 {
+            match self {
+                Color::White => 1,
+                Color::Black => -1,
+            }
+        }
+
+        // Where the king and rooks start
+        pub fn home_rank(self) -> u8 {
+            match self {
+                Color::White => 0,
+                Color::Black => 7,
+            }
+        }
+
+        pub fn pawn_rank(self) -> u8 {
+            (self.home_rank() as i8 + self.forward()) as u8
+        }
+
+        pub fn promotion_rank(self) -> u8 {
+            self.opposite().home_rank()
+        }
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+    pub struct Piece {
+        #[serde(rename = "type")]
+        pub tpe: Type,
+        pub color: Color,
+    }
+
+    impl Piece {
+        pub fn new(tpe: Type, color: Color) -> Piece {
+            Piece { tpe, color }
+        }
+
+        pub fn new_opt(tpe: Type, color: Color) -> Option<Piece> {
+            Some(Self::new(tpe, color))
+        }
+
+        // Short name such as "wN", as used in the board string
+        pub fn name(&self) -> String {
+            let c = match self.color {
+                Color::White => 'w',
+                Color::Black => 'b',
+            };
+            format!("{}{}", c, self.tpe.letter())
+        }
+
+        // FEN style: uppercase for White, lowercase for Black
+        pub fn fen_letter(&self) -> char {
+            match self.color {
+                Color::White => self.tpe.letter(),
+                Color::Black => self.tpe.letter().to_ascii_lowercase(),
+            }
+        }
+
+        pub fn from_name(name: &str) -> Option<Piece> {
+            let mut chars = name.chars();
+            let color = match chars.next() {
+                Some('w') => Color::White,
+                Some('b') => Color::Black,
+                _ => return None,
+            };
+            match (chars.next().and_then(Type::from_letter), chars.next()) {
+                (Some(tpe), None) => Some(Piece::new(tpe, color)),
+                _ => None,
+            }
+        }
+
+        pub fn promotes_at(&self, to: Location) -> bool {
+            self.tpe == Type::Pawn && to.y == self.color.promotion_rank()
+        }
+
+        // Pushes onto empty squares, two from the starting rank, then diagonal
+        // captures including en passant; promotion is left to promotes_at
+        fn moves_pawn(&self, board: &Board, from: Location) -> Vec<Location> {
+            let dy = self.color.forward();
+            let steps = if from.y == self.color.pawn_rank() {
+                2
+            } else {
+                1
+            };
+            let mut moves: Vec<Location> = WalkStrategy::new(0, dy, steps)
+                .to_walk(from)
+                .take_while(|&dest| board.get(dest).is_none())
+                .collect();
+            for &dx in [-1, 1].iter() {
+                if let Some(dest) = from.offset(dx, dy) {
+                    match board.get(dest) {
+                        Some(piece) if piece.color != self.color => moves.push(dest),
+                        None if board.en_passant == Some(dest) && board.turn == self.color => {
+                            moves.push(dest)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            moves
+        }
+
+        pub fn valid_moves(&self, board: &Board, from: Location) -> Vec<Location> {
+            let strategies = match self.tpe {
+                Type::Pawn => return self.moves_pawn(board, from),
+                Type::Bishop => vec![
+                    WalkStrategy::new(-1, -1, 7),
+                    WalkStrategy::new(-1, 1, 7),
+                    WalkStrategy::new(1, -1, 7),
+                    WalkStrategy::new(1, 1, 7),
+                ],
+                Type::Knight => vec![
+                    WalkStrategy::new(-2, -1, 1),
+                    WalkStrategy::new(-2, 1, 1),
+                    WalkStrategy::new(-1, -2, 1),
+                    WalkStrategy::new(-1, 2, 1),
+                    WalkStrategy::new(1, -2, 1),
+                    WalkStrategy::new(1, 2, 1),
+                    WalkStrategy::new(2, -1, 1),
+                    WalkStrategy::new(2, 1, 1),
+                ],
+                Type::Rook => vec![
+                    WalkStrategy::new(-1, 0, 7),
+                    WalkStrategy::new(0, -1, 7),
+                    WalkStrategy::new(0, 1, 7),
+                    WalkStrategy::new(1, 0, 7),
+                ],
+                Type::Queen => vec![
+                    WalkStrategy::new(-1, -1, 7),
+                    WalkStrategy::new(-1, 1, 7),
+                    WalkStrategy::new(1, -1, 7),
+                    WalkStrategy::new(1, 1, 7),
+                    WalkStrategy::new(-1, 0, 7),
+                    WalkStrategy::new(0, -1, 7),
+                    WalkStrategy::new(0, 1, 7),
+                    WalkStrategy::new(1, 0, 7),
+                ],
+                Type::King => vec![
+                    WalkStrategy::new(-1, -1, 1),
+                    WalkStrategy::new(-1, 1, 1),
+                    WalkStrategy::new(1, -1, 1),
+                    WalkStrategy::new(1, 1, 1),
+                    WalkStrategy::new(-1, 0, 1),
+                    WalkStrategy::new(0, -1, 1),
+                    WalkStrategy::new(0, 1, 1),
+                    WalkStrategy::new(1, 0, 1),
+                ],
+            };
+            let mut moves = Vec::new();
+            for strategy in strategies {
+                let walk = strategy.to_walk(from);
+                for dest in walk {
+                    if let Some(piece) = board.get(dest) {
+                        if piece.color != self.color {
+                            moves.push(dest);
+                        }
+                        break;
+                    }
+                    moves.push(dest)
+                }
+            }
+            moves
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Location {
+    x: u8,
+    y: u8,
+}
+
+impl Location {
+    pub fn is_dark(&self) -> bool {
+        (self.x + self.y).is_multiple_of(2)
+    }
+
+    // 'a' to 'h'
+    pub fn file(&self) -> char {
+        (b'a' + self.x) as char
+    }
+
+    // 1 to 8
+    pub fn rank(&self) -> u8 {
+        self.y + 1
+    }
+
+    // None once the step would leave the board
+    pub fn offset(&self, dx: i8, dy: i8) -> Option<Location> {
+        let (x, y) = (self.x as i8 + dx, self.y as i8 + dy);
+        if (0..8).contains(&x) && (0..8).contains(&y) {
+            Some(Location {
+                x: x as u8,
+                y: y as u8,
+            })
+        } else {
+            None
+        }
+    }
+
+    // King moves needed to get from one square to the other
+    pub fn distance_chebyshev(&self, other: Location) -> u8 {
+        self.x.abs_diff(other.x).max(self.y.abs_diff(other.y))
+    }
+
+    // Rook moves along one file and one rank, counting each square
+    pub fn distance_manhattan(&self, other: Location) -> u8 {
+        self.x.abs_diff(other.x) + self.y.abs_diff(other.y)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.file(), self.rank())
+    }
+}
+
+// Same algebraic form as Display so squares look alike across the API
+impl Serialize for Location {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for Location {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Location, Error> {
+        match s.as_bytes() {
+            [file @ b'a'..=b'h', rank @ b'1'..=b'8'] => Ok(Location {
+                x: file - b'a',
+                y: rank - b'1',
+            }),
+            _ => Err(Error::new(
+                ErrorCode::InvalidArgument,
+                format!("Invalid square {}", s),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct WalkStrategy {
+    dx: i8,
+    dy: i8,
+    max_steps: u8,
+}
+
+impl WalkStrategy {
+    pub fn new(dx: i8, dy: i8, max_steps: u8) -> WalkStrategy {
+        WalkStrategy { dx, dy, max_steps }
+    }
+
+    pub fn to_walk(self, start: Location) -> Walk {
+        Walk {
+            dx: self.dx,
+            dy: self.dy,
+            steps_left: self.max_steps,
+            cur: start,
+        }
+    }
+}
+
+struct Walk {
+    dx: i8,
+    dy: i8,
+    steps_left: u8,
+    cur: Location,
+}
+
+impl Iterator for Walk {
+    type Item = Location;
+
+    fn next(&mut self) -> Option<Location> {
+        if self.steps_left == 0 {
+            return None;
+        }
+        let next = self.cur.offset(self.dx, self.dy)?;
+        self.steps_left -= 1;
+        self.cur = next;
+        Some(next)
+    }
+}
+
+mod zobrist {
+    use super::piece::{Color, Piece};
+    use super::{CastlingRights, Location};
+
+    struct Keys {
+        pieces: [[u64; 64]; 12],
+        black_to_move: u64,
+        castling: [u64; 4],
+        en_passant: [u64; 8],
+    }
+
+    const fn splitmix64(state: u64) -> (u64, u64) {
+        let state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        (state, z ^ (z >> 31))
+    }
+
+    const fn generate() -> Keys {
+        let mut state = 0x5eed_c0de_ba5e_ba11;
+        let mut pieces = [[0; 64]; 12];
+        let mut i = 0;
+        while i < 12 {
+            let mut j = 0;
+            while j < 64 {
+                let (next, key) = splitmix64(state);
+                state = next;
+                pieces[i][j] = key;
+                j += 1;
+            }
+            i += 1;
+        }
+        let (next, black_to_move) = splitmix64(state);
+        state = next;
+        let mut castling = [0; 4];
+        let mut i = 0;
+        while i < 4 {
+            let (next, key) = splitmix64(state);
+            state = next;
+            castling[i] = key;
+            i += 1;
+        }
+        let mut en_passant = [0; 8];
+        let mut i = 0;
+        while i < 8 {
+            let (next, key) = splitmix64(state);
+            state = next;
+            en_passant[i] = key;
+            i += 1;
+        }
+        Keys {
+            pieces,
+            black_to_move,
+            castling,
+            en_passant,
+        }
+    }
+
+    static KEYS: Keys = generate();
+
+    pub fn piece(piece: Piece, loc: Location) -> u64 {
+        let idx = piece.tpe as usize * 2 + piece.color as usize;
+        KEYS.pieces[idx][loc.y as usize * 8 + loc.x as usize]
+    }
+
+    pub fn side_to_move(color: Color) -> u64 {
+        match color {
+            Color::White => 0,
+            Color::Black => KEYS.black_to_move,
+        }
+    }
+
+    pub fn castling(rights: CastlingRights) -> u64 {
+        let flags = [
+            rights.white_kingside,
+            rights.white_queenside,
+            rights.black_kingside,
+            rights.black_queenside,
+        ];
+        let mut hash = 0;
+        for (key, &flag) in KEYS.castling.iter().zip(flags.iter()) {
+            if flag {
+                hash ^= key;
+            }
+        }
+        hash
+    }
+
+    pub fn en_passant(target: Option<Location>) -> u64 {
+        match target {
+            None => 0,
+            Some(loc) => KEYS.en_passant[loc.x as usize],
+        }
+    }
+}
+
+mod bitboard {
+    use super::piece::{Color, Piece, Type};
+    use super::Location;
+
+    pub const TYPES: [Type; 6] = [
+        Type::Pawn,
+        Type::Bishop,
+        Type::Knight,
+        Type::Rook,
+        Type::Queen,
+        Type::King,
+    ];
+
+    const FILE_A: u64 = 0x0101_0101_0101_0101;
+    const FILE_H: u64 = FILE_A << 7;
+    const RANK_3: u64 = 0xff << 16;
+    const RANK_6: u64 = 0xff << 40;
+
+    const KNIGHT_JUMPS: [(i8, i8); 8] = [
+        (1, 2),
+        (2, 1),
+        (2, -1),
+        (1, -2),
+        (-1, -2),
+        (-2, -1),
+        (-2, 1),
+        (-1, 2),
+    ];
+
+    // The first four directions move towards higher square indices
+    const DIRECTIONS: [(i8, i8); 8] = [
+        (1, 0),
+        (1, 1),
+        (0, 1),
+        (-1, 1),
+        (-1, 0),
+        (-1, -1),
+        (0, -1),
+        (1, -1),
+    ];
+
+    const fn on_board(x: i8, y: i8) -> bool {
+        x >= 0 && x < 8 && y >= 0 && y < 8
+    }
+
+    const fn leaper_table(offsets: [(i8, i8); 8]) -> [u64; 64] {
+        let mut table = [0; 64];
+        let mut sq = 0;
+        while sq < 64 {
+            let (x, y) = ((sq % 8) as i8, (sq / 8) as i8);
+            let mut i = 0;
+            while i < 8 {
+                let (tx, ty) = (x + offsets[i].0, y + offsets[i].1);
+                if on_board(tx, ty) {
+                    table[sq] |= 1 << (ty * 8 + tx);
+                }
+                i += 1;
+            }
+            sq += 1;
+        }
+        table
+    }
+
+    const fn ray_table() -> [[u64; 64]; 8] {
+        let mut table = [[0; 64]; 8];
+        let mut dir = 0;
+        while dir < 8 {
+            let (dx, dy) = DIRECTIONS[dir];
+            let mut sq = 0;
+            while sq < 64 {
+                let (mut x, mut y) = ((sq % 8) as i8 + dx, (sq / 8) as i8 + dy);
+                while on_board(x, y) {
+                    table[dir][sq] |= 1 << (y * 8 + x);
+                    x += dx;
+                    y += dy;
+                }
+                sq += 1;
+            }
+            dir += 1;
+        }
+        table
+    }
+
+    static KNIGHT: [u64; 64] = leaper_table(KNIGHT_JUMPS);
+    static KING: [u64; 64] = leaper_table(DIRECTIONS);
+    static RAYS: [[u64; 64]; 8] = ray_table();
+
+    // One board per piece type and color, indexed like the Zobrist keys
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct Bitboards {
+        pieces: [u64; 12],
+        occupancy: [u64; 2],
+    }
+
+    impl Bitboards {
+        pub fn from_squares(squares: &[[Option<Piece>; 8]; 8]) -> Bitboards {
+            let mut boards = Bitboards {
+                pieces: [0; 12],
+                occupancy: [0; 2],
+            };
+            for y in 0..8 {
+                for x in 0..8 {
+                    if let Some(piece) = squares[y as usize][x as usize] {
+                        boards.toggle(piece, Location { x, y });
+                    }
+                }
+            }
+            boards
+        }
+
+        pub fn toggle(&mut self, piece: Piece, loc: Location) {
+            self.pieces[piece.tpe as usize * 2 + piece.color as usize] ^= bit(loc);
+            self.occupancy[piece.color as usize] ^= bit(loc);
+        }
+
+        pub fn pieces(&self, tpe: Type, color: Color) -> u64 {
+            self.pieces[tpe as usize * 2 + color as usize]
+        }
+
+        pub fn color(&self, color: Color) -> u64 {
+            self.occupancy[color as usize]
+        }
+
+        pub fn occupied(&self) -> u64 {
+            self.occupancy[0] | self.occupancy[1]
+        }
+    }
+
+    fn index(loc: Location) -> usize {
+        loc.y as usize * 8 + loc.x as usize
+    }
+
+    pub fn bit(loc: Location) -> u64 {
+        1 << index(loc)
+    }
+
+    // Locations of the set bits, lowest index first
+    pub fn squares(mut bb: u64) -> impl Iterator<Item = Location> {
+        std::iter::from_fn(move || {
+            if bb == 0 {
+                return None;
+            }
+            let index = bb.trailing_zeros() as u8;
+            bb &= bb - 1;
+            Some(Location {
+                x: index % 8,
+                y: index / 8,
+            })
+        })
+    }
+
+    pub fn pawn_attacks(color: Color, loc: Location) -> u64 {
+        let bit = bit(loc);
+        // Shifting off the edge of a rank wraps onto the opposite file
+        match color {
+            Color::White => ((bit << 7) & !FILE_H) | ((bit << 9) & !FILE_A),
+            Color::Black => ((bit >> 9) & !FILE_H) | ((bit >> 7) & !FILE_A),
+        }
+    }
+
+    pub fn pawn_pushes(color: Color, loc: Location, occupied: u64) -> u64 {
+        let empty = !occupied;
+        match color {
+            Color::White => {
+                let single = (bit(loc) << 8) & empty;
+                single | (((single & RANK_3) << 8) & empty)
+            }
+            Color::Black => {
+                let single = (bit(loc) >> 8) & empty;
+                single | (((single & RANK_6) >> 8) & empty)
+            }
+        }
+    }
+
+    fn ray_attacks(dir: usize, loc: Location, occupied: u64) -> u64 {
+        let ray = RAYS[dir][index(loc)];
+        let blockers = ray & occupied;
+        if blockers == 0 {
+            return ray;
+        }
+        // The nearest blocker is the lowest or highest bit depending on the direction
+        let nearest = if dir < 4 {
+            blockers.trailing_zeros()
+        } else {
+            63 - blockers.leading_zeros()
+        };
+        ray ^ RAYS[dir][nearest as usize]
+    }
+
+    pub fn rook_attacks(loc: Location, occupied: u64) -> u64 {
+        [0, 2, 4, 6]
+            .iter()
+            .fold(0, |bb, &dir| bb | ray_attacks(dir, loc, occupied))
+    }
+
+    pub fn bishop_attacks(loc: Location, occupied: u64) -> u64 {
+        [1, 3, 5, 7]
+            .iter()
+            .fold(0, |bb, &dir| bb | ray_attacks(dir, loc, occupied))
+    }
+
+    pub fn knight_attacks(loc: Location) -> u64 {
+        KNIGHT[index(loc)]
+    }
+
+    pub fn king_attacks(loc: Location) -> u64 {
+        KING[index(loc)]
+    }
+
+    pub fn attacks(piece: Piece, loc: Location, occupied: u64) -> u64 {
+        match piece.tpe {
+            Type::Pawn => pawn_attacks(piece.color, loc),
+            Type::Bishop => bishop_attacks(loc, occupied),
+            Type::Knight => knight_attacks(loc),
+            Type::Rook => rook_attacks(loc, occupied),
+            Type::Queen => rook_attacks(loc, occupied) | bishop_attacks(loc, occupied),
+            Type::King => king_attacks(loc),
+        }
+    }
+}
+
+mod random {
+    pub trait Rng {
+        fn next_u64(&mut self) -> u64;
+    }
+
+    // splitmix64: tiny, fine with any seed including zero, and the same seed
+    // always replays the same sequence
+    #[derive(Debug, Clone)]
+    pub struct SplitMix64 {
+        state: u64,
+    }
+
+    impl SplitMix64 {
+        pub fn new(seed: u64) -> SplitMix64 {
+            SplitMix64 { state: seed }
+        }
+    }
+
+    impl Rng for SplitMix64 {
+        fn next_u64(&mut self) -> u64 {
+            self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = self.state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        }
+    }
+}
+
+// Piece-square bonuses in centipawns, written from White's side with rank 8 on
+// top; Black reads the same tables mirrored
+mod pst {
+    use super::piece::{Color, Piece, Type};
+    use super::Location;
+
+    #[rustfmt::skip]
+    const PAWN: [i32; 64] = [
+         0,  0,  0,  0,  0,  0,  0,  0,
+        50, 50, 50, 50, 50, 50, 50, 50,
+        10, 10, 20, 30, 30, 20, 10, 10,
+         5,  5, 10, 25, 25, 10,  5,  5,
+         0,  0,  0, 20, 20,  0,  0,  0,
+         5, -5,-10,  0,  0,-10, -5,  5,
+         5, 10, 10,-20,-20, 10, 10,  5,
+         0,  0,  0,  0,  0,  0,  0,  0,
+    ];
+
+    #[rustfmt::skip]
+    const KNIGHT: [i32; 64] = [
+        -50,-40,-30,-30,-30,-30,-40,-50,
+        -40,-20,  0,  0,  0,  0,-20,-40,
+        -30,  0, 10, 15, 15, 10,  0,-30,
+        -30,  5, 15, 20, 20, 15,  5,-30,
+        -30,  0, 15, 20, 20, 15,  0,-30,
+        -30,  5, 10, 15, 15, 10,  5,-30,
+        -40,-20,  0,  5,  5,  0,-20,-40,
+        -50,-40,-30,-30,-30,-30,-40,-50,
+    ];
+
+    #[rustfmt::skip]
+    const BISHOP: [i32; 64] = [
+        -20,-10,-10,-10,-10,-10,-10,-20,
+        -10,  0,  0,  0,  0,  0,  0,-10,
+        -10,  0,  5, 10, 10,  5,  0,-10,
+        -10,  5,  5, 10, 10,  5,  5,-10,
+        -10,  0, 10, 10, 10, 10,  0,-10,
+        -10, 10, 10, 10, 10, 10, 10,-10,
+        -10,  5,  0,  0,  0,  0,  5,-10,
+        -20,-10,-10,-10,-10,-10,-10,-20,
+    ];
+
+    #[rustfmt::skip]
+    const ROOK: [i32; 64] = [
+         0,  0,  0,  0,  0,  0,  0,  0,
+         5, 10, 10, 10, 10, 10, 10,  5,
+        -5,  0,  0,  0,  0,  0,  0, -5,
+        -5,  0,  0,  0,  0,  0,  0, -5,
+        -5,  0,  0,  0,  0,  0,  0, -5,
+        -5,  0,  0,  0,  0,  0,  0, -5,
+        -5,  0,  0,  0,  0,  0,  0, -5,
+         0,  0,  0,  5,  5,  0,  0,  0,
+    ];
+
+    #[rustfmt::skip]
+    const QUEEN: [i32; 64] = [
+        -20,-10,-10, -5, -5,-10,-10,-20,
+        -10,  0,  0,  0,  0,  0,  0,-10,
+        -10,  0,  5,  5,  5,  5,  0,-10,
+         -5,  0,  5,  5,  5,  5,  0, -5,
+          0,  0,  5,  5,  5,  5,  0, -5,
+        -10,  5,  5,  5,  5,  5,  0,-10,
+        -10,  0,  5,  0,  0,  0,  0,-10,
+        -20,-10,-10, -5, -5,-10,-10,-20,
+    ];
+
+    #[rustfmt::skip]
+    const KING: [i32; 64] = [
+        -30,-40,-40,-50,-50,-40,-40,-30,
+        -30,-40,-40,-50,-50,-40,-40,-30,
+        -30,-40,-40,-50,-50,-40,-40,-30,
+        -30,-40,-40,-50,-50,-40,-40,-30,
+        -20,-30,-30,-40,-40,-30,-30,-20,
+        -10,-20,-20,-20,-20,-20,-20,-10,
+         20, 20,  0,  0,  0,  0, 20, 20,
+         20, 30, 10,  0,  0, 10, 30, 20,
+    ];
+
+    // Signed like the evaluation: positive is good for White
+    pub fn value(piece: Piece, at: Location) -> i32 {
+        let table = match piece.tpe {
+            Type::Pawn => &PAWN,
+            Type::Knight => &KNIGHT,
+            Type::Bishop => &BISHOP,
+            Type::Rook => &ROOK,
+            Type::Queen => &QUEEN,
+            Type::King => &KING,
+        };
+        let (x, y) = (at.x as usize, at.y as usize);
+        match piece.color {
+            Color::White => table[(7 - y) * 8 + x],
+            Color::Black => -table[y * 8 + x],
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct CastlingRights {
+    white_kingside: bool,
+    white_queenside: bool,
+    black_kingside: bool,
+    black_queenside: bool,
+}
+
+impl CastlingRights {
+    pub fn all() -> CastlingRights {
+        CastlingRights {
+            white_kingside: true,
+            white_queenside: true,
+            black_kingside: true,
+            black_queenside: true,
+        }
+    }
+
+    pub fn kingside(&self, color: piece::Color) -> bool {
+        match color {
+            piece::Color::White => self.white_kingside,
+            piece::Color::Black => self.black_kingside,
+        }
+    }
+
+    pub fn queenside(&self, color: piece::Color) -> bool {
+        match color {
+            piece::Color::White => self.white_queenside,
+            piece::Color::Black => self.black_queenside,
+        }
+    }
+
+    // Anything moving from or onto a king or rook home square loses those rights
+    pub fn revoke(&mut self, loc: Location) {
+        match (loc.x, loc.y) {
+            (4, 0) => {
+                self.white_kingside = false;
+                self.white_queenside = false;
+            }
+            (0, 0) => self.white_queenside = false,
+            (7, 0) => self.white_kingside = false,
+            (4, 7) => {
+                self.black_kingside = false;
+                self.black_queenside = false;
+            }
+            (0, 7) => self.black_queenside = false,
+            (7, 7) => self.black_kingside = false,
+            _ => {}
+        }
+    }
+
+    // The FEN castling field, "-" when no rights are left
+    pub fn to_fen(self) -> String {
+        let rights = [
+            (self.white_kingside, 'K'),
+            (self.white_queenside, 'Q'),
+            (self.black_kingside, 'k'),
+            (self.black_queenside, 'q'),
+        ];
+        let castling: String = rights
+            .iter()
+            .filter(|&&(allowed, _)| allowed)
+            .map(|&(_, c)| c)
+            .collect();
+        if castling.is_empty() {
+            "-".to_string()
+        } else {
+            castling
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Ongoing,
+    Checkmate,
+    Stalemate,
+    Resigned(piece::Color),
+    // The color whose flag fell
+    TimeForfeit(piece::Color),
+    Draw(DrawReason),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Ongoing => write!(f, "ongoing"),
+            Status::Checkmate => write!(f, "checkmate"),
+            Status::Stalemate => write!(f, "stalemate"),
+            Status::Resigned(color) => write!(f, "{} resigned", color.name()),
+            Status::TimeForfeit(color) => write!(f, "{} lost on time", color.name()),
+            Status::Draw(DrawReason::Agreement) => write!(f, "draw by agreement"),
+            Status::Draw(DrawReason::InsufficientMaterial) => {
+                write!(f, "draw by insufficient material")
+            }
+            Status::Draw(DrawReason::DeadPosition) => write!(f, "draw by dead position"),
+            Status::Draw(DrawReason::ThreefoldRepetition) => {
+                write!(f, "draw by threefold repetition")
+            }
+            Status::Draw(DrawReason::FiftyMoveRule) => write!(f, "draw by the fifty-move rule"),
+            Status::Draw(DrawReason::FivefoldRepetition) => {
+                write!(f, "draw by fivefold repetition")
+            }
+            Status::Draw(DrawReason::SeventyFiveMoveRule) => {
+                write!(f, "draw by the seventy-five move rule")
+            }
+            Status::Draw(DrawReason::TimeoutVsInsufficientMaterial) => {
+                write!(f, "draw by timeout vs insufficient material")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum DrawReason {
+    Agreement,
+    ThreefoldRepetition,
+    FiftyMoveRule,
+    FivefoldRepetition,
+    SeventyFiveMoveRule,
+    InsufficientMaterial,
+    DeadPosition,
+    // A flag fell but the opponent could never have mated
+    TimeoutVsInsufficientMaterial,
+}
+
+// Declared in the order Board::check_move runs its stages, so the first
+// failing stage is the one reported
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum MoveVerdict {
+    Legal,
+    GameNotInProgress,
+    NoPiece,
+    NotYourTurn,
+    OwnPiece,
+    Blocked,
+    NoCapture,
+    CastlingNotAllowed,
+    NotPseudoLegal,
+    InvalidPromotion,
+    LeavesKingInCheck,
+}
+
+impl MoveVerdict {
+    fn code(self) -> ErrorCode {
+        match self {
+            MoveVerdict::Legal => ErrorCode::BadRequest,
+            MoveVerdict::GameNotInProgress => ErrorCode::GameOver,
+            MoveVerdict::NoPiece => ErrorCode::NoPiece,
+            MoveVerdict::NotYourTurn => ErrorCode::NotYourTurn,
+            MoveVerdict::OwnPiece
+            | MoveVerdict::Blocked
+            | MoveVerdict::NoCapture
+            | MoveVerdict::CastlingNotAllowed
+            | MoveVerdict::NotPseudoLegal => ErrorCode::InvalidMove,
+            MoveVerdict::InvalidPromotion => ErrorCode::InvalidPromotion,
+            MoveVerdict::LeavesKingInCheck => ErrorCode::KingInCheck,
+        }
+    }
+
+    fn refuse(self, message: String) -> (MoveVerdict, Error) {
+        (self, Error::new(self.code(), message))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum GameResult {
+    Resigned(piece::Color),
+    TimeForfeit(piece::Color),
+    Draw(DrawReason),
+}
+
+// Time is charged to the side to move while the clock runs
+#[derive(Debug, Copy, Clone)]
+struct Clock {
+    base: Duration,
+    white: Duration,
+    black: Duration,
+    increment: Duration,
+    // Cleared once the game is over, so a finished game stops losing time
+    running_since: Option<Instant>,
+}
+
+impl Clock {
+    fn new(base: Duration, increment: Duration) -> Clock {
+        Clock {
+            base,
+            white: base,
+            black: base,
+            increment,
+            running_since: None,
+        }
+    }
+
+    fn time_mut(&mut self, color: piece::Color) -> &mut Duration {
+        match color {
+            piece::Color::White => &mut self.white,
+            piece::Color::Black => &mut self.black,
+        }
+    }
+
+    // Counts the stretch that is still running against the side to move
+    fn remaining(&self, color: piece::Color, turn: piece::Color) -> Duration {
+        let stored = match color {
+            piece::Color::White => self.white,
+            piece::Color::Black => self.black,
+        };
+        match self.running_since {
+            Some(since) if color == turn => stored.saturating_sub(since.elapsed()),
+            _ => stored,
+        }
+    }
+
+    fn start(&mut self) {
+        if self.running_since.is_none() {
+            self.running_since = Some(Instant::now());
+        }
+    }
+
+    fn stop(&mut self, turn: piece::Color) {
+        if let Some(since) = self.running_since.take() {
+            let left = self.time_mut(turn);
+            *left = left.saturating_sub(since.elapsed());
+        }
+    }
+
+    // Ends the mover's turn; sync_clock starts the opponent's
+    fn press(&mut self, mover: piece::Color) {
+        self.stop(mover);
+        let increment = self.increment;
+        *self.time_mut(mover) += increment;
+    }
+}
+
+#[derive(Clone)]
+struct Board {
+    squares: [[Option<piece::Piece>; 8]; 8],
+    // The same position as `squares`, one u64 per piece type and color
+    bitboards: bitboard::Bitboards,
+    turn: piece::Color,
+    castling: CastlingRights,
+    en_passant: Option<Location>,
+    // Halfmoves since the last capture or pawn move
+    halfmove_clock: u32,
+    // Starts at 1 and goes up once Black has moved
+    fullmove_number: u32,
+    hash: u64,
+    // Sum of pst::value over the pieces, kept up to date by set like the hash
+    positional: i32,
+    result: Option<GameResult>,
+    draw_offer: Option<piece::Color>,
+    // The side asking to take back its last move, until the other side answers or moves
+    takeback_request: Option<piece::Color>,
+    history: Vec<HistoryEntry>,
+    // Moves taken back by goto, latest first, so they can be replayed
+    future: Vec<HistoryEntry>,
+    // While set, pieces can be placed freely and no moves are allowed
+    setup: bool,
+    // A promotion move waiting for its piece; no other move is allowed until then
+    pending_promotion: Option<(Location, Location)>,
+    // None for an untimed game
+    clock: Option<Clock>,
+    // Set by /seed so AI choices replay exactly; unseeded games use the time
+    rng: Option<random::SplitMix64>,
+    // Pseudo-legal moves per color, keyed by the Zobrist hash they were generated for
+    move_cache: RefCell<[Option<CachedMoves>; 2]>,
+    // Legal moves per from-square, built on top of move_cache
+    legal_cache: RefCell<VecDeque<CachedLegalMoves>>,
+}
+
+// A square labelled with its name, so the label stays right whichever way the
+// cells are ordered. An empty square is just the label.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+struct CellView {
+    square: Location,
+    #[serde(flatten)]
+    piece: Option<piece::Piece>,
+}
+
+// An owned, read-only copy of the public state, with cells from a1 to h8 or
+// from h8 to a1 when seen from black's side
+#[derive(Debug, Clone, Serialize)]
+struct BoardView {
+    cells: Vec<CellView>,
+    turn: piece::Color,
+    status: Status,
+    last_move: Option<Move>,
+    // Rights in the FEN form such as "KQkq", so they always match /fen
+    castling: String,
+    en_passant_target: Option<Location>,
+    halfmove_clock: u32,
+    fullmove_number: u32,
+    // Moves played to reach this position, and how many more goto can replay
+    ply: usize,
+    upcoming_plies: usize,
+    setup: bool,
+    pending_promotion: Option<Location>,
+    clock: Option<ClockView>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ClockView {
+    white_ms: u64,
+    black_ms: u64,
+    increment_ms: u64,
+    running: bool,
+}
+
+#[derive(Debug, Clone)]
+struct CachedMoves {
+    key: u64,
+    moves: Vec<Move>,
+}
+
+// The pinned piece can't leave the line between its king and the pinner
+#[derive(Debug, Clone)]
+struct Pin {
+    pinned: Location,
+    pinner: Location,
+    // One step from the king towards the pinner
+    direction: (i8, i8),
+    // From next to the king out to the pinner, inclusive
+    ray: Vec<Location>,
+}
+
+// 32 bytes of squares, a flags byte, an en passant byte and two u16 clocks
+const POSITION_BYTES: usize = 38;
+// Piece codes in the byte encoding are 1 + the index here, with 8 added for black
+const PIECE_CODES: [piece::Type; 6] = [
+    piece::Type::Pawn,
+    piece::Type::Knight,
+    piece::Type::Bishop,
+    piece::Type::Rook,
+    piece::Type::Queen,
+    piece::Type::King,
+];
+
+const LEGAL_CACHE_SIZE: usize = 64;
+
+#[derive(Debug, Clone)]
+struct CachedLegalMoves {
+    key: u64,
+    from: Location,
+    moves: Vec<Move>,
+}
+
+impl Board {
+    pub fn new() -> Board {
+        use piece::{Color, Piece, Type};
+        let squares = [
+            [
+                Piece::new_opt(Type::Rook, Color::White),
+                Piece::new_opt(Type::Knight, Color::White),
+                Piece::new_opt(Type::Bishop, Color::White),
+                Piece::new_opt(Type::Queen, Color::White),
+                Piece::new_opt(Type::King, Color::White),
+                Piece::new_opt(Type::Bishop, Color::White),
+                Piece::new_opt(Type::Knight, Color::White),
+                Piece::new_opt(Type::Rook, Color::White),
+            ],
+            [
+                Piece::new_opt(Type::Pawn, Color::White),
+                Piece::new_opt(Type::Pawn, Color::White),
+                Piece::new_opt(Type::Pawn, Color::White),
+                Piece::new_opt(Type::Pawn, Color::White),
+                Piece::new_opt(Type::Pawn, Color::White),
+                Piece::new_opt(Type::Pawn, Color::White),
+                Piece::new_opt(Type::Pawn, Color::White),
+                Piece::new_opt(Type::Pawn, Color::White),
+            ],
+            [None, None, None, None, None, None, None, None],
+            [None, None, None, None, None, None, None, None],
+            [None, None, None, None, None, None, None, None],
+            [None, None, None, None, None, None, None, None],
+            [
+                Piece::new_opt(Type::Pawn, Color::Black),
+                Piece::new_opt(Type::Pawn, Color::Black),
+                Piece::new_opt(Type::Pawn, Color::Black),
+                Piece::new_opt(Type::Pawn, Color::Black),
+                Piece::new_opt(Type::Pawn, Color::Black),
+                Piece::new_opt(Type::Pawn, Color::Black),
+                Piece::new_opt(Type::Pawn, Color::Black),
+                Piece::new_opt(Type::Pawn, Color::Black),
+            ],
+            [
+                Piece::new_opt(Type::Rook, Color::Black),
+                Piece::new_opt(Type::Knight, Color::Black),
+                Piece::new_opt(Type::Bishop, Color::Black),
+                Piece::new_opt(Type::Queen, Color::Black),
+                Piece::new_opt(Type::King, Color::Black),
+                Piece::new_opt(Type::Bishop, Color::Black),
+                Piece::new_opt(Type::Knight, Color::Black),
+                Piece::new_opt(Type::Rook, Color::Black),
+            ],
+        ];
+        let mut board = Board {
+            bitboards: bitboard::Bitboards::from_squares(&squares),
+            squares,
+            turn: Color::White,
+            castling: CastlingRights::all(),
+            en_passant: None,
+            halfmove_clock: 0,
+            fullmove_number: 1,
+            hash: 0,
+            positional: 0,
+            result: None,
+            draw_offer: None,
+            takeback_request: None,
+            history: Vec::new(),
+            future: Vec::new(),
+            setup: false,
+            pending_promotion: None,
+            clock: None,
+            rng: None,
+            move_cache: RefCell::new([None, None]),
+            legal_cache: RefCell::new(VecDeque::new()),
+        };
+        board.hash = board.compute_zobrist();
+        board.positional = board.compute_positional();
+        board
+    }
+
+    pub fn cells_oriented(
+        &self,
+        perspective: piece::Color,
+    ) -> Vec<(Location, Option<piece::Piece>)> {
+        let mut cells = Vec::with_capacity(64);
+        for y in 0..8 {
+            for x in 0..8 {
+                cells.push((Location { x, y }, self.get(Location { x, y })));
+            }
+        }
+        if perspective == piece::Color::Black {
+            cells.reverse();
+        }
+        cells
+    }
+
+    // The history is the only part that grows with the game, and move generation
+    // never reads it, so copies made just to try moves leave it behind
+    fn without_history(&self) -> Board {
+        Board {
+            squares: self.squares,
+            bitboards: self.bitboards,
+            turn: self.turn,
+            castling: self.castling,
+            en_passant: self.en_passant,
+            halfmove_clock: self.halfmove_clock,
+            fullmove_number: self.fullmove_number,
+            hash: self.hash,
+            positional: self.positional,
+            result: self.result,
+            draw_offer: self.draw_offer,
+            takeback_request: None,
+            history: Vec::new(),
+            future: Vec::new(),
+            setup: self.setup,
+            pending_promotion: None,
+            clock: None,
+            rng: None,
+            move_cache: self.move_cache.clone(),
+            legal_cache: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    pub fn snapshot(&self) -> BoardView {
+        self.snapshot_oriented(piece::Color::White)
+    }
+
+    // Reads only the last history entry, so handing out a view stays cheap in long games.
+    // The cells run in the same order as cells_oriented.
+    pub fn snapshot_oriented(&self, perspective: piece::Color) -> BoardView {
+        BoardView {
+            cells: self
+                .cells_oriented(perspective)
+                .into_iter()
+                .map(|(square, piece)| CellView { square, piece })
+                .collect(),
+            turn: self.turn,
+            status: self.status(),
+            last_move: self.last_move(),
+            castling: self.castling.to_fen(),
+            en_passant_target: self.en_passant(),
+            halfmove_clock: self.halfmove_clock,
+            fullmove_number: self.fullmove_number,
+            ply: self.ply(),
+            upcoming_plies: self.upcoming_plies(),
+            setup: self.setup,
+            pending_promotion: self.pending_promotion.map(|(_, to)| to),
+            clock: self.clock.map(|clock| ClockView {
+                white_ms: clock.remaining(piece::Color::White, self.turn).as_millis() as u64,
+                black_ms: clock.remaining(piece::Color::Black, self.turn).as_millis() as u64,
+                increment_ms: clock.increment.as_millis() as u64,
+                running: clock.running_since.is_some(),
+            }),
+        }
+    }
+
+    pub fn zobrist(&self) -> u64 {
+        self.hash
+    }
+
+    fn compute_zobrist(&self) -> u64 {
+        let mut hash = zobrist::side_to_move(self.turn)
+            ^ zobrist::castling(self.castling)
+            ^ zobrist::en_passant(self.en_passant);
+        for y in 0..8 {
+            for x in 0..8 {
+                if let Some(piece) = self.get(Location { x, y }) {
+                    hash ^= zobrist::piece(piece, Location { x, y });
+                }
+            }
+        }
+        hash
+    }
+
+    fn compute_positional(&self) -> i32 {
+        let mut positional = 0;
+        for y in 0..8 {
+            for x in 0..8 {
+                if let Some(piece) = self.get(Location { x, y }) {
+                    positional += pst::value(piece, Location { x, y });
+                }
+            }
+        }
+        positional
+    }
+
+    fn validate_move(
+        &self,
+        from: Location,
+        to: Location,
+        promote: Option<piece::Type>,
+    ) -> Result<Move, Error> {
+        self.check_move(from, to, promote).map_err(|(_, e)| e)
+    }
+
+    fn check_move(
+        &self,
+        from: Location,
+        to: Location,
+        promote: Option<piece::Type>,
+    ) -> Result<Move, (MoveVerdict, Error)> {
+        self.ensure_in_progress()
+            .map_err(|e| (MoveVerdict::GameNotInProgress, e))?;
+        let piece = match self.get(from) {
+            None => Err(MoveVerdict::NoPiece.refuse(format!("No piece at {}", from))),
+            Some(p) => Ok(p),
+        }?;
+        let () = if piece.color == self.turn {
+            Ok(())
+        } else {
+            Err(MoveVerdict::NotYourTurn.refuse(format!(
+                "Not your turn: {} at {} but it is {} to move",
+                piece.name(),
+                from,
+                self.turn.name()
+            )))
+        }?;
+        // The cached legal list answers the common case; pseudo-legal moves only
+        // decide which error to report
+        let legal = self.legal_moves_from(from);
+        let reachable = legal.iter().any(|mv| mv.to == to)
+            || self
+                .pseudo_legal_moves(piece.color)
+                .iter()
+                .any(|mv| mv.from == from && mv.to == to);
+        let () = if reachable {
+            Ok(())
+        } else {
+            Err(self.unreachable(piece, from, to))
+        }?;
+        let promote = match (piece.promotes_at(to), promote) {
+            (_, Some(tpe)) if !PROMOTIONS.contains(&tpe) => {
+                Err(format!("Invalid promotion piece {}", tpe.letter()))
+            }
+            (true, None) => Ok(Some(piece::Type::Queen)),
+            (true, Some(tpe)) => Ok(Some(tpe)),
+            (false, None) => Ok(None),
+            (false, Some(_)) => Err(format!(
+                "Invalid promotion: {} from {} to {} does not promote",
+                piece.name(),
+                from,
+                to
+            )),
+        }
+        .map_err(|e| MoveVerdict::InvalidPromotion.refuse(e))?;
+        let mv = Move { from, to, promote };
+        let () = if !legal.contains(&mv) {
+            Err(MoveVerdict::LeavesKingInCheck.refuse(format!(
+                "King in check: {} from {} to {} leaves the {} king in check",
+                piece.name(),
+                from,
+                to,
+                piece.color.name()
+            )))
+        } else {
+            Ok(())
+        }?;
+        Ok(mv)
+    }
+
+    // Why a piece of the side to move has no pseudo-legal move to the square
+    fn unreachable(
+        &self,
+        piece: piece::Piece,
+        from: Location,
+        to: Location,
+    ) -> (MoveVerdict, Error) {
+        let (dx, dy) = (to.x as i8 - from.x as i8, to.y as i8 - from.y as i8);
+        let forward = piece.color.forward();
+        if let Some(own) = self.get(to).filter(|p| p.color == piece.color) {
+            MoveVerdict::OwnPiece.refuse(format!(
+                "Invalid move: {} from {} cannot capture its own {} on {}",
+                piece.name(),
+                from,
+                own.name(),
+                to
+            ))
+        } else if let Some(at) = self.blocker(piece, from, to) {
+            MoveVerdict::Blocked.refuse(format!(
+                "Invalid move: {} from {} to {} is blocked by {} on {}",
+                piece.name(),
+                from,
+                to,
+                self.get(at).unwrap().name(),
+                at
+            ))
+        } else if piece.tpe == piece::Type::Pawn && dx.abs() == 1 && dy == forward {
+            MoveVerdict::NoCapture.refuse(format!(
+                "Invalid move: {} from {} has nothing to capture on {}",
+                piece.name(),
+                from,
+                to
+            ))
+        } else if piece.tpe == piece::Type::King && dx.abs() == 2 && dy == 0 {
+            MoveVerdict::CastlingNotAllowed.refuse(format!(
+                "Invalid move: {} from {} cannot castle to {}",
+                piece.name(),
+                from,
+                to
+            ))
+        } else {
+            MoveVerdict::NotPseudoLegal.refuse(format!(
+                "Invalid move: {} from {} cannot reach {}",
+                piece.name(),
+                from,
+                to
+            ))
+        }
+    }
+
+    // The first occupied square on the way when the piece moves along the
+    // line from one square to the other but something stands in between
+    fn blocker(&self, piece: piece::Piece, from: Location, to: Location) -> Option<Location> {
+        use piece::Type;
+        let (dx, dy) = (to.x as i8 - from.x as i8, to.y as i8 - from.y as i8);
+        let forward = piece.color.forward();
+        let straight = dx == 0 || dy == 0;
+        let diagonal = dx.abs() == dy.abs();
+        let along = match piece.tpe {
+            Type::Rook => straight,
+            Type::Bishop => diagonal,
+            Type::Queen => straight || diagonal,
+            Type::Pawn => {
+                dx == 0
+                    && (dy == forward || (dy == 2 * forward && from.y == piece.color.pawn_rank()))
+            }
+            Type::Knight | Type::King => false,
+        };
+        if !along || from == to {
+            return None;
+        }
+        let mut at = from;
+        loop {
+            at = at.offset(dx.signum(), dy.signum())?;
+            // Pawns cannot push onto an occupied square, so the target blocks too
+            if at == to && piece.tpe != Type::Pawn {
+                return None;
+            }
+            if self.get(at).is_some() {
+                return Some(at);
+            }
+            if at == to {
+                return None;
+            }
+        }
+    }
+
+    // The verdict of the full legality pipeline rather than just its error
+    pub fn explain_move(
+        &self,
+        from: Location,
+        to: Location,
+        promote: Option<piece::Type>,
+    ) -> (MoveVerdict, String) {
+        match self.check_move(from, to, promote) {
+            Ok(mv) => (
+                MoveVerdict::Legal,
+                format!("Legal: {}", self.san(mv, &self.all_legal_moves())),
+            ),
+            Err((verdict, e)) => (verdict, e.message),
+        }
+    }
+
+    pub fn parse_uci(&self, s: &str) -> Result<Move, Error> {
+        let mv = s.parse::<Move>()?;
+        self.validate_move(mv.from, mv.to, mv.promote)
+    }
+
+    // UCI when it reads as two squares, SAN otherwise
+    pub fn parse_move(&self, s: &str) -> Result<Move, Error> {
+        match s.parse::<Move>() {
+            Ok(mv) => self.validate_move(mv.from, mv.to, mv.promote),
+            Err(_) => self.parse_san(s),
+        }
+    }
+
+    // Runs `f` on a copy with the line played out, so the board itself never changes
+    pub fn with_moves<T>(
+        &self,
+        moves: &[Move],
+        f: impl FnOnce(&mut Board) -> T,
+    ) -> Result<T, Error> {
+        let mut line = self.clone();
+        // The line is hypothetical, so it shouldn't wait on a piece being chosen or run a clock
+        line.pending_promotion = None;
+        line.clock = None;
+        for (index, mv) in moves.iter().enumerate() {
+            line.step(mv.from, mv.to, mv.promote)
+                .map_err(|e| e.context(|e| format!("{} at index {} ({})", e, index, mv)))?;
+        }
+        Ok(f(&mut line))
+    }
+
+    // All or nothing: on an error the board is left as it was
+    pub fn play(&mut self, moves: &[String]) -> Result<(), Error> {
+        let mut next = self.clone();
+        for (index, token) in moves.iter().enumerate() {
+            next.parse_move(token)
+                .and_then(|mv| next.step(mv.from, mv.to, mv.promote))
+                .map_err(|e| e.context(|e| format!("{} at index {} ({})", e, index, token)))?;
+        }
+        *self = next;
+        Ok(())
+    }
+
+    pub fn promotes(&self, from: Location, to: Location) -> bool {
+        self.get(from).is_some_and(|piece| piece.promotes_at(to))
+    }
+
+    // For frontends that ask for the piece after the pawn is dropped: the move is
+    // checked now and completed by promote, with the board left as it was until then
+    pub fn defer_promotion(&mut self, from: Location, to: Location) -> Result<(), Error> {
+        self.ensure_pending_promotion(from, to)?;
+        let mv = self.validate_move(from, to, None)?;
+        let () = if mv.promote.is_some() {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorCode::InvalidPromotion,
+                format!("Invalid promotion: {} to {} does not promote", from, to),
+            ))
+        }?;
+        self.pending_promotion = Some((from, to));
+        Ok(())
+    }
+
+    pub fn promote(&mut self, tpe: piece::Type) -> Result<(), Error> {
+        let (from, to) = match self.pending_promotion {
+            None => Err(Error::new(
+                ErrorCode::NoPendingPromotion,
+                "No promotion pending".to_string(),
+            )),
+            Some(pending) => Ok(pending),
+        }?;
+        self.step(from, to, Some(tpe))
+    }
+
+    fn ensure_pending_promotion(&self, from: Location, to: Location) -> Result<(), Error> {
+        match self.pending_promotion {
+            Some(pending) if pending != (from, to) => Err(Error::new(
+                ErrorCode::PromotionPending,
+                format!(
+                    "Promotion pending: choose a piece for {} to {} first",
+                    pending.0, pending.1
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_legal(&self, from: Location, to: Location, promote: Option<piece::Type>) -> bool {
+        self.validate_move(from, to, promote).is_ok()
+    }
+
+    pub fn step(
+        &mut self,
+        from: Location,
+        to: Location,
+        promote: Option<piece::Type>,
+    ) -> Result<(), Error> {
+        // A move sent after the flag fell ends the game instead of being played
+        self.check_flag();
+        self.ensure_pending_promotion(from, to)?;
+        let mv = self.validate_move(from, to, promote)?;
+        self.pending_promotion = None;
+        // Playing from an earlier ply starts a new line, so the old continuation goes
+        self.future.clear();
+        // Moving on withdraws your own offer, so it can't be accepted moves later
+        if self.draw_offer == Some(self.turn) {
+            self.draw_offer = None;
+        }
+        // Only the requester's opponent can move here, which turns the request down
+        self.takeback_request = None;
+        let hash = self.hash;
+        let turn = self.turn;
+        if let Some(clock) = &mut self.clock {
+            clock.press(turn);
+        }
+        let undo = self.make_move(mv);
+        self.history.push(HistoryEntry { mv, undo, hash });
+        self.sync_clock();
+        // validate_move rejects these, so this only fires on a move generation bug
+        debug_assert!(
+            !self.is_in_check(self.turn.opposite()),
+            "Illegal position after {}: {} left in check\n{}",
+            mv,
+            self.turn.opposite().name(),
+            self
+        );
+        Ok(())
+    }
+
+    pub fn undo(&mut self) -> Result<(), Error> {
+        // The pending move is the latest one, so undo takes back just that
+        if self.pending_promotion.take().is_some() {
+            return Ok(());
+        }
+        // Checkmate and automatic draws come from the position, so undoing them resumes play
+        let () = match self.result {
+            None => Ok(()),
+            Some(_) => Err(Error::new(
+                ErrorCode::GameOver,
+                format!("Game is over: {}", self.status()),
+            )),
+        }?;
+        let HistoryEntry { mv, undo, .. } = match self.history.pop() {
+            None => Err(Error::new(
+                ErrorCode::NothingToUndo,
+                "No moves to undo".to_string(),
+            )),
+            Some(entry) => Ok(entry),
+        }?;
+        // The later moves followed the one just taken back
+        self.future.clear();
+        self.takeback_request = None;
+        self.stop_clock();
+        self.unmake_move(mv, undo);
+        self.sync_clock();
+        Ok(())
+    }
+
+    // Moves to the position after `ply` moves, keeping the rest of the line so
+    // later calls can step forward again
+    pub fn goto(&mut self, ply: usize) -> Result<(), Error> {
+        let last = self.history.len() + self.future.len();
+        let () = if ply <= last {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorCode::InvalidArgument,
+                format!("Invalid ply {}: the game has {} plies", ply, last),
+            ))
+        }?;
+        self.pending_promotion = None;
+        self.takeback_request = None;
+        self.stop_clock();
+        while self.history.len() > ply {
+            let entry = self.history.pop().unwrap();
+            self.unmake_move(entry.mv, entry.undo);
+            self.future.push(entry);
+        }
+        while self.history.len() < ply {
+            let entry = self.future.pop().unwrap();
+            let undo = self.make_move(entry.mv);
+            self.history.push(HistoryEntry { undo, ..entry });
+        }
+        self.sync_clock();
+        Ok(())
+    }
+
+    // Restarts both clocks from `base`, with White's or Black's time running at once
+    pub fn set_time_control(&mut self, base: Duration, increment: Duration) -> Result<(), Error> {
+        self.ensure_in_progress()?;
+        self.clock = Some(Clock::new(base, increment));
+        self.sync_clock();
+        Ok(())
+    }
+
+    // Ends the game once the side to move has no time left. The clock only runs for
+    // that side, so its flag is the only one that can have fallen.
+    pub fn check_flag(&mut self) {
+        let turn = self.turn;
+        let flagged = self.clock.is_some_and(|clock| {
+            clock.running_since.is_some() && clock.remaining(turn, turn).is_zero()
+        });
+        if !flagged {
+            return;
+        }
+        self.result = Some(if self.can_mate(turn.opposite()) {
+            GameResult::TimeForfeit(turn)
+        } else {
+            GameResult::Draw(DrawReason::TimeoutVsInsufficientMaterial)
+        });
+        self.draw_offer = None;
+        self.sync_clock();
+    }
+
+    // Charges the side to move for the time so far; pair with sync_clock around any
+    // change that can end the game or switch sides
+    fn stop_clock(&mut self) {
+        let turn = self.turn;
+        if let Some(clock) = &mut self.clock {
+            clock.stop(turn);
+        }
+    }
+
+    // The clock runs only while the game is in progress
+    fn sync_clock(&mut self) {
+        if self.clock.is_none() {
+            return;
+        }
+        if self.status() == Status::Ongoing {
+            self.clock.as_mut().unwrap().start();
+        } else {
+            self.stop_clock();
+        }
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = Some(random::SplitMix64::new(seed));
+    }
+
+    // Seeds each random choice from the game's generator, so the same seed and the
+    // same requests give the same replies
+    pub fn next_seed(&mut self) -> u64 {
+        match &mut self.rng {
+            Some(rng) => random::Rng::next_u64(rng),
+            None => time_seed(),
+        }
+    }
+
+    pub fn ply(&self) -> usize {
+        self.history.len()
+    }
+
+    // Plies after the current one that goto can still replay
+    pub fn upcoming_plies(&self) -> usize {
+        self.future.len()
+    }
+
+    // All reads of the square array go through here, and all writes through set,
+    // so the x/y order lives in one place
+    pub fn get(&self, loc: Location) -> Option<piece::Piece> {
+        self.squares[loc.y as usize][loc.x as usize]
+    }
+
+    pub fn en_passant(&self) -> Option<Location> {
+        self.en_passant
+    }
+
+    pub fn last_move(&self) -> Option<Move> {
+        self.history.last().map(|entry| entry.mv)
+    }
+
+    // Squares the last move changed and what is on them now, found by comparing
+    // against the position before it so castling and en passant need no special case
+    pub fn last_move_diff(&self) -> Vec<(Location, Option<piece::Piece>)> {
+        let entry = match self.history.last() {
+            None => return Vec::new(),
+            Some(&entry) => entry,
+        };
+        let mut before = self.without_history();
+        before.unmake_move(entry.mv, entry.undo);
+        self.cells_oriented(piece::Color::White)
+            .into_iter()
+            .filter(|&(loc, piece)| before.get(loc) != piece)
+            .collect()
+    }
+
+    // SAN depends on the position before each move, so rewind to the start and replay
+    pub fn san_history(&self) -> Vec<String> {
+        let mut board = self.clone();
+        let history = std::mem::take(&mut board.history);
+        for entry in history.iter().rev() {
+            board.unmake_move(entry.mv, entry.undo);
+        }
+        let moves: Vec<Move> = history.iter().map(|entry| entry.mv).collect();
+        board.san_line(&moves)
+    }
+
+    // Export format: the seven standard tags plus Termination, then the movetext
+    // wrapped at 80 columns, ending in a comment that says how the game ended
+    pub fn to_pgn(&self) -> String {
+        use piece::Color;
+        let mut start = self.without_history();
+        for entry in self.history.iter().rev() {
+            start.unmake_move(entry.mv, entry.undo);
+        }
+        let status = self.status();
+        let result = match status {
+            Status::Ongoing => "*",
+            Status::Checkmate if self.turn == Color::Black => "1-0",
+            Status::Checkmate => "0-1",
+            Status::Resigned(Color::Black) | Status::TimeForfeit(Color::Black) => "1-0",
+            Status::Resigned(Color::White) | Status::TimeForfeit(Color::White) => "0-1",
+            Status::Stalemate | Status::Draw(_) => "1/2-1/2",
+        };
+        let termination = match status {
+            Status::Ongoing => None,
+            Status::Checkmate => Some(format!("{} checkmated", capitalize(self.turn.name()))),
+            Status::Resigned(color) => Some(format!("{} resigns", capitalize(color.name()))),
+            Status::TimeForfeit(color) => {
+                Some(format!("{} loses on time", capitalize(color.name())))
+            }
+            Status::Stalemate => Some("Draw by stalemate".to_string()),
+            Status::Draw(_) => Some(capitalize(&status.to_string())),
+        };
+        let mut tags = vec![
+            ("Event", "?".to_string()),
+            ("Site", "?".to_string()),
+            ("Date", "????.??.??".to_string()),
+            ("Round", "?".to_string()),
+            ("White", "?".to_string()),
+            ("Black", "?".to_string()),
+            ("Result", result.to_string()),
+        ];
+        let start_fen = start.to_fen();
+        if start_fen != Board::new().to_fen() {
+            tags.push(("SetUp", "1".to_string()));
+            tags.push(("FEN", start_fen));
+        }
+        // The times as of the last move, so a saved game only changes when a move is made
+        if let Some(clock) = &self.clock {
+            tags.push((
+                "TimeControl",
+                format!(
+                    "{}+{}",
+                    clock.base.as_secs_f64(),
+                    clock.increment.as_secs_f64()
+                ),
+            ));
+            tags.push(("WhiteClock", format_clock(clock.white)));
+            tags.push(("BlackClock", format_clock(clock.black)));
+        }
+        let ended = match status {
+            Status::Ongoing => "Unterminated",
+            Status::TimeForfeit(_) | Status::Draw(DrawReason::TimeoutVsInsufficientMaterial) => {
+                "Time forfeit"
+            }
+            _ => "Normal",
+        };
+        tags.push(("Termination", ended.to_string()));
+
+        let mut tokens = Vec::new();
+        let (mut number, mut turn) = (start.fullmove_number, start.turn);
+        for (ply, san) in self.san_history().into_iter().enumerate() {
+            match turn {
+                Color::White => tokens.push(format!("{}.", number)),
+                Color::Black if ply == 0 => tokens.push(format!("{}...", number)),
+                Color::Black => {}
+            }
+            tokens.push(san);
+            if turn == Color::Black {
+                number += 1;
+            }
+            turn = turn.opposite();
+        }
+        if let Some(termination) = termination {
+            tokens.push(format!("{{{}}}", termination));
+        }
+        tokens.push(result.to_string());
+
+        let mut pgn = String::new();
+        for (name, value) in tags {
+            pgn.push_str(&format!("[{} \"{}\"]\n", name, value));
+        }
+        pgn.push('\n');
+        let mut line = String::new();
+        for token in tokens {
+            if !line.is_empty() && line.len() + 1 + token.len() > 80 {
+                pgn.push_str(&line);
+                pgn.push('\n');
+                line.clear();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&token);
+        }
+        pgn.push_str(&line);
+        pgn.push('\n');
+        pgn
+    }
+
+    pub fn san_line(&self, moves: &[Move]) -> Vec<String> {
+        let mut board = self.without_history();
+        let mut sans = Vec::new();
+        for &mv in moves {
+            let legal_moves = board.all_legal_moves();
+            sans.push(board.san(mv, &legal_moves));
+            board.make_move(mv);
+        }
+        sans
+    }
+
+    pub fn make_move(&mut self, mv: Move) -> Undo {
+        use piece::Type;
+        let Move { from, to, promote } = mv;
+        let piece = self.get(from).unwrap();
+        let placed = match promote {
+            None => piece,
+            Some(tpe) => piece::Piece::new(tpe, piece.color),
+        };
+        let irreversible = self.is_irreversible(&mv);
+        let undo = Undo {
+            captured: None,
+            castling: self.castling,
+            en_passant: self.en_passant,
+            halfmove_clock: self.halfmove_clock,
+        };
+        let capture_at = en_passant_capture(piece, mv, self.en_passant).unwrap_or(to);
+        let captured = self.get(capture_at);
+        self.set(capture_at, None);
+        self.set(from, None);
+        self.set(to, Some(placed));
+        if let Some((rook_from, rook_to)) = castling_rook(piece, mv) {
+            let rook = self.get(rook_from);
+            self.set(rook_from, None);
+            self.set(rook_to, rook);
+        }
+        self.hash ^= zobrist::castling(self.castling);
+        self.castling.revoke(from);
+        self.castling.revoke(to);
+        self.hash ^= zobrist::castling(self.castling);
+        self.hash ^= zobrist::en_passant(self.en_passant);
+        self.en_passant = if piece.tpe == Type::Pawn && from.distance_chebyshev(to) == 2 {
+            Some(Location {
+                x: from.x,
+                y: (from.y + to.y) / 2,
+            })
+        } else {
+            None
+        };
+        self.hash ^= zobrist::en_passant(self.en_passant);
+        self.halfmove_clock = if irreversible {
+            0
+        } else {
+            self.halfmove_clock + 1
+        };
+        if self.turn == piece::Color::Black {
+            self.fullmove_number += 1;
+        }
+        self.hash ^= zobrist::side_to_move(self.turn) ^ zobrist::side_to_move(self.turn.opposite());
+        self.turn = self.turn.opposite();
+        debug_assert_eq!(self.validate_invariants(), Ok(()));
+        Undo { captured, ..undo }
+    }
+
+    pub fn unmake_move(&mut self, mv: Move, undo: Undo) {
+        let Move { from, to, promote } = mv;
+        self.turn = self.turn.opposite();
+        if self.turn == piece::Color::Black {
+            self.fullmove_number -= 1;
+        }
+        self.hash ^= zobrist::side_to_move(self.turn) ^ zobrist::side_to_move(self.turn.opposite());
+        self.hash ^= zobrist::en_passant(self.en_passant) ^ zobrist::en_passant(undo.en_passant);
+        self.en_passant = undo.en_passant;
+        self.halfmove_clock = undo.halfmove_clock;
+        self.hash ^= zobrist::castling(self.castling) ^ zobrist::castling(undo.castling);
+        self.castling = undo.castling;
+        let placed = self.get(to).unwrap();
+        let piece = match promote {
+            None => placed,
+            Some(_) => piece::Piece::new(piece::Type::Pawn, placed.color),
+        };
+        if let Some((rook_from, rook_to)) = castling_rook(piece, mv) {
+            let rook = self.get(rook_to);
+            self.set(rook_to, None);
+            self.set(rook_from, rook);
+        }
+        self.set(to, None);
+        self.set(from, Some(piece));
+        if let Some(captured) = undo.captured {
+            let capture_at = en_passant_capture(piece, mv, self.en_passant).unwrap_or(to);
+            self.set(capture_at, Some(captured));
+        }
+        debug_assert_eq!(self.validate_invariants(), Ok(()));
+    }
+
+    // Passes the turn, which no real game allows: for analysis only, and never
+    // with the side to move in check since the king could then be taken
+    pub fn make_null_move(&mut self) -> Undo {
+        debug_assert!(!self.is_in_check(self.turn));
+        let undo = Undo {
+            captured: None,
+            castling: self.castling,
+            en_passant: self.en_passant,
+            halfmove_clock: self.halfmove_clock,
+        };
+        self.hash ^= zobrist::en_passant(self.en_passant) ^ zobrist::en_passant(None);
+        self.en_passant = None;
+        self.halfmove_clock += 1;
+        if self.turn == piece::Color::Black {
+            self.fullmove_number += 1;
+        }
+        self.hash ^= zobrist::side_to_move(self.turn) ^ zobrist::side_to_move(self.turn.opposite());
+        self.turn = self.turn.opposite();
+        undo
+    }
+
+    pub fn unmake_null_move(&mut self, undo: Undo) {
+        self.turn = self.turn.opposite();
+        if self.turn == piece::Color::Black {
+            self.fullmove_number -= 1;
+        }
+        self.hash ^= zobrist::side_to_move(self.turn) ^ zobrist::side_to_move(self.turn.opposite());
+        self.hash ^= zobrist::en_passant(self.en_passant) ^ zobrist::en_passant(undo.en_passant);
+        self.en_passant = undo.en_passant;
+        self.halfmove_clock = undo.halfmove_clock;
+    }
+
+    // Checked after every make and unmake in debug builds, so a move generation bug
+    // fails loudly at the move that caused it instead of surfacing positions later
+    pub fn validate_invariants(&self) -> Result<(), String> {
+        for &color in [piece::Color::White, piece::Color::Black].iter() {
+            let kings = self.bitboards.pieces(piece::Type::King, color).count_ones();
+            if kings != 1 {
+                return Err(format!(
+                    "Broken invariant: {} has {} kings",
+                    color.name(),
+                    kings
+                ));
+            }
+        }
+        if self.bitboards != bitboard::Bitboards::from_squares(&self.squares) {
+            return Err("Broken invariant: bitboards disagree with the squares".to_string());
+        }
+        if self.hash != self.compute_zobrist() {
+            return Err("Broken invariant: hash disagrees with the position".to_string());
+        }
+        if self.positional != self.compute_positional() {
+            return Err(
+                "Broken invariant: positional score disagrees with the position".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    // Every square change goes through here so the array, bitboards and hash agree
+    fn set(&mut self, at: Location, piece: Option<piece::Piece>) {
+        if let Some(old) = self.get(at) {
+            self.bitboards.toggle(old, at);
+            self.hash ^= zobrist::piece(old, at);
+            self.positional -= pst::value(old, at);
+        }
+        if let Some(new) = piece {
+            self.bitboards.toggle(new, at);
+            self.hash ^= zobrist::piece(new, at);
+            self.positional += pst::value(new, at);
+        }
+        self.squares[at.y as usize][at.x as usize] = piece;
+    }
+
+    fn moves_from(&self, from: Location) -> Vec<Move> {
+        let piece = match self.get(from) {
+            None => return Vec::new(),
+            Some(piece) => piece,
+        };
+        let mut moves = Vec::new();
+        for to in piece.valid_moves(self, from) {
+            push_moves(&mut moves, piece, from, to);
+        }
+        if piece.tpe == piece::Type::King {
+            moves.extend(self.castling_moves(piece.color, from));
+        }
+        moves
+    }
+
+    fn castling_moves(&self, color: piece::Color, from: Location) -> Vec<Move> {
+        use piece::{Piece, Type};
+        let rank = color.home_rank();
+        if from != (Location { x: 4, y: rank }) {
+            return Vec::new();
+        }
+        let rook = Piece::new_opt(Type::Rook, color);
+        let empty = |x: u8| self.get(Location { x, y: rank }).is_none();
+        let attacked = self.attacked_squares(color.opposite());
+        let safe = |x: u8| attacked & bitboard::bit(Location { x, y: rank }) == 0;
+        let mut moves = Vec::new();
+        if self.castling.kingside(color)
+            && self.get(Location { x: 7, y: rank }) == rook
+            && empty(5)
+            && empty(6)
+            && safe(4)
+            && safe(5)
+            && safe(6)
+        {
+            moves.push(Move {
+                from,
+                to: Location { x: 6, y: rank },
+                promote: None,
+            });
+        }
+        if self.castling.queenside(color)
+            && self.get(Location { x: 0, y: rank }) == rook
+            && empty(1)
+            && empty(2)
+            && empty(3)
+            && safe(4)
+            && safe(3)
+            && safe(2)
+        {
+            moves.push(Move {
+                from,
+                to: Location { x: 2, y: rank },
+                promote: None,
+            });
+        }
+        moves
+    }
+
+    pub fn pseudo_legal_moves(&self, color: piece::Color) -> Vec<Move> {
+        // Every mutation goes through the hash, so a matching key can't be stale
+        if let Some(cached) = &self.move_cache.borrow()[color as usize] {
+            if cached.key == self.hash {
+                return cached.moves.clone();
+            }
+        }
+        #[cfg(test)]
+        GENERATED_MOVES.with(|count| count.set(count.get() + 1));
+        let moves = self.generate_pseudo_legal_moves(color);
+        debug_assert!(same_moves(&moves, &self.array_pseudo_legal_moves(color)));
+        self.move_cache.borrow_mut()[color as usize] = Some(CachedMoves {
+            key: self.hash,
+            moves: moves.clone(),
+        });
+        moves
+    }
+
+    fn generate_pseudo_legal_moves(&self, color: piece::Color) -> Vec<Move> {
+        use piece::{Piece, Type};
+        let occupied = self.bitboards.occupied();
+        let own = self.bitboards.color(color);
+        let mut enemies = self.bitboards.color(color.opposite());
+        if let (Some(target), true) = (self.en_passant, self.turn == color) {
+            enemies |= bitboard::bit(target);
+        }
+        let mut moves = Vec::new();
+        for &tpe in bitboard::TYPES.iter() {
+            let piece = Piece::new(tpe, color);
+            for from in bitboard::squares(self.bitboards.pieces(tpe, color)) {
+                let targets = match tpe {
+                    Type::Pawn => {
+                        bitboard::pawn_pushes(color, from, occupied)
+                            | (bitboard::pawn_attacks(color, from) & enemies)
+                    }
+                    _ => bitboard::attacks(piece, from, occupied) & !own,
+                };
+                for to in bitboard::squares(targets) {
+                    push_moves(&mut moves, piece, from, to);
+                }
+                if tpe == Type::King {
+                    moves.extend(self.castling_moves(color, from));
+                }
+            }
+        }
+        moves
+    }
+
+    // The original square-by-square generator, kept to cross-check the bitboards
+    fn array_pseudo_legal_moves(&self, color: piece::Color) -> Vec<Move> {
+        let mut moves = Vec::new();
+        for y in 0..8 {
+            for x in 0..8 {
+                if let Some(piece) = self.get(Location { x, y }) {
+                    if piece.color == color {
+                        moves.extend(self.moves_from(Location { x, y }));
+                    }
+                }
+            }
+        }
+        moves
+    }
+
+    // A board in setup mode may be missing a king, so nothing is legal on it
+    // until setup is finished
+    fn legal_moves(&mut self) -> Vec<Move> {
+        if self.setup {
+            return Vec::new();
+        }
+        let moves = self.pseudo_legal_moves(self.turn);
+        moves
+            .into_iter()
+            .filter(|&mv| self.keeps_king_safe(mv))
+            .collect()
+    }
+
+    // For a pseudo-legal move of the side to move
+    fn keeps_king_safe(&mut self, mv: Move) -> bool {
+        let color = self.turn;
+        let piece = self.get(mv.from).unwrap();
+        // King moves only need the destination checked, and castling already
+        // checked every square the king crosses
+        if piece.tpe == piece::Type::King {
+            castling_rook(piece, mv).is_some()
+                || !self.is_attacked_ignoring(mv.to, color.opposite(), Some(mv.from))
+        } else {
+            let undo = self.make_move(mv);
+            let in_check = self.is_in_check(color);
+            self.unmake_move(mv, undo);
+            !in_check
+        }
+    }
+
+    // Uniform over the legal moves; None once there are none
+    pub fn random_move(&self, rng: &mut impl random::Rng) -> Option<Move> {
+        let moves = self.all_legal_moves();
+        if moves.is_empty() {
+            return None;
+        }
+        Some(moves[(rng.next_u64() % moves.len() as u64) as usize])
+    }
+
+    // Stops at the first legal move, which is usually the first pseudo-legal one
+    pub fn has_legal_moves(&self) -> bool {
+        if self.setup {
+            return false;
+        }
+        let mut board = self.without_history();
+        self.pseudo_legal_moves(self.turn)
+            .into_iter()
+            .any(|mv| board.keeps_king_safe(mv))
+    }
+
+    // A dragging UI asks about the same square over and over between moves. Keyed by
+    // hash like move_cache, so any change to the board misses; the oldest entry goes first.
+    pub fn legal_moves_from(&self, from: Location) -> Vec<Move> {
+        // The hash doesn't change on entering setup, so this goes before the cache
+        if self.setup {
+            return Vec::new();
+        }
+        let hit = self
+            .legal_cache
+            .borrow()
+            .iter()
+            .find(|cached| cached.key == self.hash && cached.from == from)
+            .map(|cached| cached.moves.clone());
+        if let Some(moves) = hit {
+            return moves;
+        }
+        let mut board = self.without_history();
+        let moves: Vec<Move> = self
+            .pseudo_legal_moves(self.turn)
+            .into_iter()
+            .filter(|&mv| mv.from == from && board.keeps_king_safe(mv))
+            .collect();
+        let mut cache = self.legal_cache.borrow_mut();
+        if cache.len() == LEGAL_CACHE_SIZE {
+            cache.pop_front();
+        }
+        cache.push_back(CachedLegalMoves {
+            key: self.hash,
+            from,
+            moves: moves.clone(),
+        });
+        moves
+    }
+
+    pub fn all_legal_moves(&self) -> Vec<Move> {
+        self.without_history().legal_moves()
+    }
+
+    // Counts the leaves of the legal move tree, the standard move generation check
+    pub fn perft(&mut self, depth: u8) -> u64 {
+        if depth == 0 {
+            return 1;
+        }
+        let mut nodes = 0;
+        for mv in self.legal_moves() {
+            let undo = self.make_move(mv);
+            nodes += self.perft(depth - 1);
+            self.unmake_move(mv, undo);
+        }
+        nodes
+    }
+
+    // Like perft, but gives up with None once more than `budget` leaves are counted
+    fn perft_within(&mut self, depth: u8, budget: &mut u64) -> Option<u64> {
+        if depth == 0 {
+            *budget = budget.checked_sub(1)?;
+            return Some(1);
+        }
+        let mut nodes = 0;
+        for mv in self.legal_moves() {
+            let undo = self.make_move(mv);
+            let counted = self.perft_within(depth - 1, budget);
+            self.unmake_move(mv, undo);
+            nodes += counted?;
+        }
+        Some(nodes)
+    }
+
+    // Perft split by root move, for bisecting a move generation bug. None if the
+    // tree has more than max_nodes leaves.
+    pub fn divide(&self, depth: u8, max_nodes: u64) -> Option<Vec<(Move, u64)>> {
+        if depth == 0 {
+            return Some(Vec::new());
+        }
+        let mut board = self.without_history();
+        let mut budget = max_nodes;
+        board
+            .legal_moves()
+            .into_iter()
+            .map(|mv| {
+                let undo = board.make_move(mv);
+                let nodes = board.perft_within(depth - 1, &mut budget);
+                board.unmake_move(mv, undo);
+                nodes.map(|nodes| (mv, nodes))
+            })
+            .collect()
+    }
+
+    // Every move for the side to move that forces mate within `moves` of its own
+    // moves, each with its shortest mating line
+    pub fn mating_moves(&self, moves: u8) -> Vec<(Move, Vec<Move>)> {
+        let mut board = self.without_history();
+        let mut mates = Vec::new();
+        for mv in board.legal_moves() {
+            let undo = board.make_move(mv);
+            let line = (1..=moves).find_map(|n| board.mate_defence(n));
+            board.unmake_move(mv, undo);
+            if let Some(mut line) = line {
+                line.insert(0, mv);
+                mates.push((mv, line));
+            }
+        }
+        mates
+    }
+
+    fn forced_mate(&mut self, moves: u8) -> Option<Vec<Move>> {
+        for mv in self.legal_moves() {
+            let undo = self.make_move(mv);
+            let line = self.mate_defence(moves);
+            self.unmake_move(mv, undo);
+            if let Some(mut line) = line {
+                line.insert(0, mv);
+                return Some(line);
+            }
+        }
+        None
+    }
+
+    // The defender has just been moved against; None if any reply escapes mate within
+    // `moves`, otherwise the reply that holds out longest
+    fn mate_defence(&mut self, moves: u8) -> Option<Vec<Move>> {
+        let replies = self.legal_moves();
+        if replies.is_empty() {
+            return Some(Vec::new()).filter(|_| self.is_in_check(self.turn));
+        }
+        let mut longest: Option<Vec<Move>> = None;
+        for reply in replies {
+            let undo = self.make_move(reply);
+            let line = (1..moves).find_map(|n| self.forced_mate(n));
+            self.unmake_move(reply, undo);
+            let mut line = line?;
+            line.insert(0, reply);
+            if longest
+                .as_ref()
+                .is_none_or(|longest| line.len() > longest.len())
+            {
+                longest = Some(line);
+            }
+        }
+        longest
+    }
+
+    pub fn san(&self, mv: Move, legal_moves: &[Move]) -> String {
+        use piece::Type;
+        let Move { from, to, promote } = mv;
+        let piece = self.get(from).unwrap();
+        let capture = self.is_capture(mv);
+        let mut san = String::new();
+        if let Some((rook_from, _)) = castling_rook(piece, mv) {
+            san.push_str(if rook_from.x == 7 { "O-O" } else { "O-O-O" });
+        } else if piece.tpe == Type::Pawn {
+            if capture {
+                san.push(from.file());
+                san.push('x');
+            }
+        } else {
+            san.push(piece.tpe.letter());
+            let rivals: Vec<Location> = legal_moves
+                .iter()
+                .filter(|other| {
+                    other.to == to && other.from != from && self.get(other.from) == Some(piece)
+                })
+                .map(|other| other.from)
+                .collect();
+            if !rivals.is_empty() {
+                if rivals.iter().all(|rival| rival.x != from.x) {
+                    san.push(from.file());
+                } else if rivals.iter().all(|rival| rival.y != from.y) {
+                    san.push_str(&from.rank().to_string());
+                } else {
+                    san.push_str(&from.to_string());
+                }
+            }
+            if capture {
+                san.push('x');
+            }
+        }
+        if castling_rook(piece, mv).is_none() {
+            san.push_str(&to.to_string());
+        }
+        if let Some(tpe) = promote {
+            san.push('=');
+            san.push(tpe.letter());
+        }
+        let mut next = self.without_history();
+        next.make_move(mv);
+        if next.is_in_check(next.turn) {
+            if next.all_legal_moves().is_empty() {
+                san.push('#');
+            } else {
+                san.push('+');
+            }
+        }
+        san
+    }
+
+    // Captures and pawn moves reset the fifty-move count; castling does not
+    pub fn is_irreversible(&self, mv: &Move) -> bool {
+        let pawn_move = self
+            .get(mv.from)
+            .is_some_and(|piece| piece.tpe == piece::Type::Pawn);
+        pawn_move || self.is_capture(*mv)
+    }
+
+    pub fn is_capture(&self, mv: Move) -> bool {
+        self.captured(mv).is_some()
+    }
+
+    // What the move would take and from where, which differs from `to` en passant
+    pub fn captured(&self, mv: Move) -> Option<(Location, piece::Piece)> {
+        let piece = self.get(mv.from)?;
+        let at = en_passant_capture(piece, mv, self.en_passant).unwrap_or(mv.to);
+        self.get(at).map(|captured| (at, captured))
+    }
+
+    pub fn parse_san(&self, s: &str) -> Result<Move, Error> {
+        let normalize = |san: &str| {
+            san.trim_end_matches(|c| "+#!?".contains(c))
+                .replace('=', "")
+                .replace('0', "O")
+        };
+        let wanted = normalize(s);
+        let legal_moves = self.all_legal_moves();
+        legal_moves
+            .iter()
+            .copied()
+            .find(|&mv| normalize(&self.san(mv, &legal_moves)) == wanted)
+            .ok_or_else(|| Error::new(ErrorCode::InvalidMove, format!("Invalid move {}", s)))
+    }
+
+    pub fn from_pgn(pgn: &str) -> Result<Board, Error> {
+        let mut board = match pgn_tag(pgn, "FEN") {
+            Some(fen) => Board::from_fen(&fen).map_err(|e| {
+                Error::new(ErrorCode::InvalidFen, format!("Invalid FEN tag: {}", e))
+            })?,
+            None => Board::new(),
+        };
+        for (ply, token) in pgn_movetext(pgn).iter().enumerate() {
+            let mv = board
+                .parse_san(token)
+                .map_err(|e| e.context(|e| format!("{} at ply {}", e, ply + 1)))?;
+            board
+                .step(mv.from, mv.to, mv.promote)
+                .map_err(|e| e.context(|e| format!("{} at ply {}", e, ply + 1)))?;
+        }
+        // A result the moves don't explain was a resignation, a flag falling, or an
+        // agreed or claimed draw
+        if board.status() == Status::Ongoing {
+            let on_time = pgn_tag(pgn, "Termination").as_deref() == Some("Time forfeit");
+            board.result = match (pgn_tag(pgn, "Result").as_deref(), on_time) {
+                (Some("1-0"), false) => Some(GameResult::Resigned(piece::Color::Black)),
+                (Some("0-1"), false) => Some(GameResult::Resigned(piece::Color::White)),
+                (Some("1-0"), true) => Some(GameResult::TimeForfeit(piece::Color::Black)),
+                (Some("0-1"), true) => Some(GameResult::TimeForfeit(piece::Color::White)),
+                (Some("1/2-1/2"), true) => {
+                    Some(GameResult::Draw(DrawReason::TimeoutVsInsufficientMaterial))
+                }
+                (Some("1/2-1/2"), false) => Some(GameResult::Draw(
+                    board.claimable_draw().unwrap_or(DrawReason::Agreement),
+                )),
+                _ => None,
+            };
+        }
+        if let Some(raw) = pgn_tag(pgn, "TimeControl") {
+            let mut clock = match parse_time_control(&raw) {
+                Some((base, increment)) => Ok(Clock::new(base, increment)),
+                None => Err(Error::new(
+                    ErrorCode::BadRequest,
+                    format!("Invalid clock tag TimeControl {}", raw),
+                )),
+            }?;
+            for &(color, name) in [
+                (piece::Color::White, "WhiteClock"),
+                (piece::Color::Black, "BlackClock"),
+            ]
+            .iter()
+            {
+                if let Some(raw) = pgn_tag(pgn, name) {
+                    *clock.time_mut(color) = parse_clock(&raw).ok_or_else(|| {
+                        Error::new(
+                            ErrorCode::BadRequest,
+                            format!("Invalid clock tag {} {}", name, raw),
+                        )
+                    })?;
+                }
+            }
+            board.clock = Some(clock);
+            board.sync_clock();
+        }
+        Ok(board)
+    }
+
+    pub fn load_from(path: &Path) -> Result<Board, String> {
+        let pgn = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        Board::from_pgn(&pgn).map_err(|e| format!("Could not load {}: {}", path.display(), e))
+    }
+
+    pub fn is_square_attacked(&self, sq: Location, by: piece::Color) -> bool {
+        self.is_attacked_ignoring(sq, by, None)
+    }
+
+    pub fn attacked_squares(&self, by: piece::Color) -> u64 {
+        let occupied = self.bitboards.occupied();
+        let mut attacked = 0;
+        for &tpe in bitboard::TYPES.iter() {
+            let piece = piece::Piece::new(tpe, by);
+            for from in bitboard::squares(self.bitboards.pieces(tpe, by)) {
+                attacked |= bitboard::attacks(piece, from, occupied);
+            }
+        }
+        attacked
+    }
+
+    // Every piece of `by` attacking the square, found the same way as below
+    pub fn attackers(&self, sq: Location, by: piece::Color) -> u64 {
+        use piece::Type;
+        let occupied = self.bitboards.occupied();
+        let theirs = |tpe: Type| self.bitboards.pieces(tpe, by);
+        (bitboard::knight_attacks(sq) & theirs(Type::Knight))
+            | (bitboard::king_attacks(sq) & theirs(Type::King))
+            | (bitboard::pawn_attacks(by.opposite(), sq) & theirs(Type::Pawn))
+            | (bitboard::rook_attacks(sq, occupied) & (theirs(Type::Rook) | theirs(Type::Queen)))
+            | (bitboard::bishop_attacks(sq, occupied)
+                & (theirs(Type::Bishop) | theirs(Type::Queen)))
+    }
+
+    // Absolute pins on `color`: walks out from the king and keeps each line where the
+    // first piece is ours and the next is an enemy slider moving along that line
+    pub fn pins(&self, color: piece::Color) -> Vec<Pin> {
+        use piece::Type;
+        let king = match bitboard::squares(self.bitboards.pieces(Type::King, color)).next() {
+            None => return Vec::new(),
+            Some(king) => king,
+        };
+        let mut pins = Vec::new();
+        for &(dx, dy) in [
+            (-1, -1),
+            (-1, 0),
+            (-1, 1),
+            (0, -1),
+            (0, 1),
+            (1, -1),
+            (1, 0),
+            (1, 1),
+        ]
+        .iter()
+        {
+            let slider = if dx == 0 || dy == 0 {
+                Type::Rook
+            } else {
+                Type::Bishop
+            };
+            let mut ray = Vec::new();
+            let mut pinned = None;
+            let mut sq = king;
+            while let Some(next) = sq.offset(dx, dy) {
+                sq = next;
+                ray.push(sq);
+                match (self.get(sq), pinned) {
+                    (None, _) => {}
+                    (Some(piece), None) if piece.color == color => pinned = Some(sq),
+                    (Some(piece), Some(pinned))
+                        if piece.color != color
+                            && (piece.tpe == slider || piece.tpe == Type::Queen) =>
+                    {
+                        pins.push(Pin {
+                            pinned,
+                            pinner: sq,
+                            direction: (dx, dy),
+                            ray,
+                        });
+                        break;
+                    }
+                    _ => break,
+                }
+            }
+        }
+        pins
+    }
+
+    // Pieces of `color` the other side attacks and nothing of ours defends. The king
+    // is left out, since it being attacked is check rather than a loose piece.
+    pub fn hanging_pieces(&self, color: piece::Color) -> Vec<Location> {
+        let ours = self.bitboards.color(color) & !self.bitboards.pieces(piece::Type::King, color);
+        let attacked = self.attacked_squares(color.opposite());
+        bitboard::squares(ours & attacked)
+            .filter(|&sq| self.attackers(sq, color) == 0)
+            .collect()
+    }
+
+    // Casts attacks outward from the square and intersects them with each kind of
+    // attacker. `ignore` is treated as empty so a king can't hide behind itself.
+    fn is_attacked_ignoring(
+        &self,
+        sq: Location,
+        by: piece::Color,
+        ignore: Option<Location>,
+    ) -> bool {
+        use piece::Type;
+        let ignored = ignore.map_or(0, bitboard::bit);
+        let occupied = self.bitboards.occupied() & !ignored;
+        let theirs = |tpe: Type| self.bitboards.pieces(tpe, by) & !ignored;
+        let attacked = bitboard::knight_attacks(sq) & theirs(Type::Knight) != 0
+            || bitboard::king_attacks(sq) & theirs(Type::King) != 0
+            // A pawn of ours on this square would attack exactly the squares their pawns attack from
+            || bitboard::pawn_attacks(by.opposite(), sq) & theirs(Type::Pawn) != 0
+            || bitboard::rook_attacks(sq, occupied) & (theirs(Type::Rook) | theirs(Type::Queen)) != 0
+            || bitboard::bishop_attacks(sq, occupied) & (theirs(Type::Bishop) | theirs(Type::Queen))
+                != 0;
+        debug_assert_eq!(attacked, self.array_is_attacked_ignoring(sq, by, ignore));
+        attacked
+    }
+
+    // Looks outward from the square for each kind of attacker, one square at a time
+    fn array_is_attacked_ignoring(
+        &self,
+        sq: Location,
+        by: piece::Color,
+        ignore: Option<Location>,
+    ) -> bool {
+        use piece::Type;
+        let at = |loc: Location| {
+            if Some(loc) == ignore {
+                None
+            } else {
+                self.get(loc)
+            }
+        };
+        let attacker = |dx: i8, dy: i8, types: &[Type]| {
+            sq.offset(dx, dy)
+                .and_then(at)
+                .is_some_and(|p| p.color == by && types.contains(&p.tpe))
+        };
+        let knight_jumps = [
+            (1, 2),
+            (2, 1),
+            (2, -1),
+            (1, -2),
+            (-1, -2),
+            (-2, -1),
+            (-2, 1),
+            (-1, 2),
+        ];
+        if knight_jumps
+            .iter()
+            .any(|&(dx, dy)| attacker(dx, dy, &[Type::Knight]))
+        {
+            return true;
+        }
+        let king_steps = [
+            (1, 0),
+            (1, 1),
+            (0, 1),
+            (-1, 1),
+            (-1, 0),
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+        ];
+        if king_steps
+            .iter()
+            .any(|&(dx, dy)| attacker(dx, dy, &[Type::King]))
+        {
+            return true;
+        }
+        // Pawns attack diagonally forward, so look one rank behind from their side
+        let pawn_dy = match by {
+            piece::Color::White => -1,
+            piece::Color::Black => 1,
+        };
+        if attacker(-1, pawn_dy, &[Type::Pawn]) || attacker(1, pawn_dy, &[Type::Pawn]) {
+            return true;
+        }
+        for &(dx, dy) in king_steps.iter() {
+            let sliders: &[Type] = if dx == 0 || dy == 0 {
+                &[Type::Rook, Type::Queen]
+            } else {
+                &[Type::Bishop, Type::Queen]
+            };
+            let mut cur = sq.offset(dx, dy);
+            while let Some(loc) = cur {
+                if let Some(piece) = at(loc) {
+                    if piece.color == by && sliders.contains(&piece.tpe) {
+                        return true;
+                    }
+                    break;
+                }
+                cur = loc.offset(dx, dy);
+            }
+        }
+        false
+    }
+
+    pub fn is_in_check(&self, color: piece::Color) -> bool {
+        let kings = self.bitboards.pieces(piece::Type::King, color);
+        match bitboard::squares(kings).next() {
+            None => false,
+            Some(king) => self.is_square_attacked(king, color.opposite()),
+        }
+    }
+
+    fn ensure_in_progress(&self) -> Result<(), Error> {
+        if self.setup {
+            return Err(Error::new(
+                ErrorCode::SetupMode,
+                "Board is in setup mode".to_string(),
+            ));
+        }
+        match self.status() {
+            Status::Ongoing => Ok(()),
+            status => Err(Error::new(
+                ErrorCode::GameOver,
+                format!("Game is over: {}", status),
+            )),
+        }
+    }
+
+    // Editing the board makes the move history meaningless, so it starts over
+    pub fn begin_setup(&mut self) {
+        self.setup = true;
+        self.pending_promotion = None;
+        self.clock = None;
+        self.history.clear();
+        self.future.clear();
+        self.result = None;
+        self.draw_offer = None;
+        self.takeback_request = None;
+    }
+
+    pub fn place(&mut self, at: Location, piece: Option<piece::Piece>) -> Result<(), Error> {
+        let () = if self.setup {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorCode::NotSetupMode,
+                "Board is not in setup mode".to_string(),
+            ))
+        }?;
+        self.set(at, piece);
+        Ok(())
+    }
+
+    // Setup-only, so it can't be used to skip a turn mid-game
+    pub fn set_turn(&mut self, color: piece::Color) -> Result<(), Error> {
+        let () = if self.setup {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorCode::NotSetupMode,
+                "Board is not in setup mode".to_string(),
+            ))
+        }?;
+        self.turn = color;
+        // The target is only valid for the side that would capture
+        self.en_passant = None;
+        self.hash = self.compute_zobrist();
+        Ok(())
+    }
+
+    pub fn finish_setup(&mut self, turn: Option<piece::Color>) -> Result<(), Error> {
+        let () = if self.setup {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorCode::NotSetupMode,
+                "Board is not in setup mode".to_string(),
+            ))
+        }?;
+        // Worked out on a copy, so a position that fails validation leaves the
+        // board in setup exactly as it was
+        let mut board = self.without_history();
+        if let Some(turn) = turn {
+            board.turn = turn;
+        }
+        // Only keep castling rights whose king and rook are still at home
+        for &color in [piece::Color::White, piece::Color::Black].iter() {
+            for &rook_x in [0, 7].iter() {
+                if !board.castling_home(color, rook_x) {
+                    board.castling.revoke(Location {
+                        x: rook_x,
+                        y: color.home_rank(),
+                    });
+                }
+            }
+        }
+        board.en_passant = None;
+        board.halfmove_clock = 0;
+        board.fullmove_number = 1;
+        board.hash = board.compute_zobrist();
+        board.validate_position()?;
+        self.turn = board.turn;
+        self.castling = board.castling;
+        self.en_passant = board.en_passant;
+        self.halfmove_clock = board.halfmove_clock;
+        self.fullmove_number = board.fullmove_number;
+        self.hash = board.hash;
+        self.setup = false;
+        Ok(())
+    }
+
+    fn castling_home(&self, color: piece::Color, rook_x: u8) -> bool {
+        use piece::{Piece, Type};
+        let y = color.home_rank();
+        self.get(Location { x: 4, y }) == Piece::new_opt(Type::King, color)
+            && self.get(Location { x: rook_x, y }) == Piece::new_opt(Type::Rook, color)
+    }
+
+    pub fn from_fen(fen: &str) -> Result<Board, Error> {
+        let board = Board::parse_fen(fen).map_err(|e| Error::new(ErrorCode::InvalidFen, e))?;
+        board.validate_position()?;
+        Ok(board)
+    }
+
+    // Only the syntax; from_fen goes on to check the position makes sense
+    fn parse_fen(fen: &str) -> Result<Board, String> {
+        use piece::{Color, Piece, Type};
+        let fields: Vec<&str> = fen.split_whitespace().collect();
+        // The clocks are often left off, so they default to 0 and 1
+        let () = if (4..=6).contains(&fields.len()) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Invalid FEN: expected 4 to 6 fields, got {}",
+                fields.len()
+            ))
+        }?;
+        let mut board = Board::new();
+        for loc in bitboard::squares(board.bitboards.occupied()) {
+            board.set(loc, None);
+        }
+        let ranks: Vec<&str> = fields[0].split('/').collect();
+        let () = if ranks.len() == 8 {
+            Ok(())
+        } else {
+            Err(format!(
+                "Invalid FEN: expected 8 ranks, got {}",
+                ranks.len()
+            ))
+        }?;
+        for (i, rank) in ranks.iter().enumerate() {
+            let y = 7 - i;
+            let mut x = 0;
+            for c in rank.chars() {
+                match (c.to_digit(10), Type::from_letter(c)) {
+                    (Some(n @ 1..=8), _) => x += n as usize,
+                    (None, Some(tpe)) if x < 8 => {
+                        let color = if c.is_ascii_uppercase() {
+                            Color::White
+                        } else {
+                            Color::Black
+                        };
+                        board.set(
+                            Location {
+                                x: x as u8,
+                                y: y as u8,
+                            },
+                            Piece::new_opt(tpe, color),
+                        );
+                        x += 1;
+                    }
+                    _ => return Err(format!("Invalid FEN: bad rank {}", rank)),
+                }
+            }
+            if x != 8 {
+                return Err(format!("Invalid FEN: bad rank {}", rank));
+            }
+        }
+        board.turn = match fields[1] {
+            "w" => Color::White,
+            "b" => Color::Black,
+            other => return Err(format!("Invalid FEN: bad side to move {}", other)),
+        };
+        board.castling = CastlingRights {
+            white_kingside: false,
+            white_queenside: false,
+            black_kingside: false,
+            black_queenside: false,
+        };
+        if fields[2] != "-" {
+            for c in fields[2].chars() {
+                let right = match c {
+                    'K' => &mut board.castling.white_kingside,
+                    'Q' => &mut board.castling.white_queenside,
+                    'k' => &mut board.castling.black_kingside,
+                    'q' => &mut board.castling.black_queenside,
+                    _ => return Err(format!("Invalid FEN: bad castling rights {}", fields[2])),
+                };
+                if *right {
+                    return Err(format!("Invalid FEN: bad castling rights {}", fields[2]));
+                }
+                *right = true;
+            }
+        }
+        board.en_passant = match fields[3] {
+            "-" => None,
+            square => Some(
+                square
+                    .parse::<Location>()
+                    .map_err(|_| format!("Invalid FEN: bad en passant square {}", square))?,
+            ),
+        };
+        if let Some(clock) = fields.get(4) {
+            board.halfmove_clock = clock
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid FEN: bad halfmove clock {}", clock))?;
+        }
+        if let Some(number) = fields.get(5) {
+            board.fullmove_number = match number.parse::<u32>() {
+                Ok(n) if n >= 1 => Ok(n),
+                _ => Err(format!("Invalid FEN: bad fullmove number {}", number)),
+            }?;
+        }
+        board.hash = board.compute_zobrist();
+        Ok(board)
+    }
+
+    pub fn to_fen(&self) -> String {
+        let mut ranks = Vec::new();
+        for row in self.squares.iter().rev() {
+            let mut rank = String::new();
+            let mut empty = 0;
+            for cell in row.iter() {
+                match cell {
+                    None => empty += 1,
+                    Some(piece) => {
+                        if empty > 0 {
+                            rank.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+                        rank.push(piece.fen_letter());
+                    }
+                }
+            }
+            if empty > 0 {
+                rank.push_str(&empty.to_string());
+            }
+            ranks.push(rank);
+        }
+        let turn = match self.turn {
+            piece::Color::White => "w",
+            piece::Color::Black => "b",
+        };
+        let castling = self.castling.to_fen();
+        let en_passant = self
+            .en_passant
+            .map_or("-".to_string(), |target| target.to_string());
+        format!(
+            "{} {} {} {} {} {}",
+            ranks.join("/"),
+            turn,
+            castling,
+            en_passant,
+            self.halfmove_clock,
+            self.fullmove_number
+        )
+    }
+
+    // 32 bytes of squares from a1 to h8, two per byte with the lower square in the
+    // low nibble, then turn and castling flags, the en passant square (0xFF for
+    // none) and both clocks as little-endian u16s
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; POSITION_BYTES];
+        for y in 0..8 {
+            for x in 0..8 {
+                let code = self.squares[y][x].map_or(0, |piece| {
+                    let tpe = PIECE_CODES.iter().position(|&t| t == piece.tpe).unwrap() as u8 + 1;
+                    match piece.color {
+                        piece::Color::White => tpe,
+                        piece::Color::Black => tpe | 8,
+                    }
+                });
+                let i = y * 8 + x;
+                bytes[i / 2] |= code << (4 * (i % 2));
+            }
+        }
+        let flags = [
+            self.turn == piece::Color::Black,
+            self.castling.white_kingside,
+            self.castling.white_queenside,
+            self.castling.black_kingside,
+            self.castling.black_queenside,
+        ];
+        for (bit, &set) in flags.iter().enumerate() {
+            bytes[32] |= (set as u8) << bit;
+        }
+        bytes[33] = self
+            .en_passant
+            .map_or(0xFF, |target| target.y * 8 + target.x);
+        let halfmove = self.halfmove_clock.min(u16::MAX as u32) as u16;
+        let fullmove = self.fullmove_number.min(u16::MAX as u32) as u16;
+        bytes[34..36].copy_from_slice(&halfmove.to_le_bytes());
+        bytes[36..38].copy_from_slice(&fullmove.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Board, Error> {
+        use piece::{Color, Piece};
+        let () = if bytes.len() == POSITION_BYTES {
+            Ok(())
+        } else {
+            Err(invalid_bytes(format!(
+                "expected {} bytes, got {}",
+                POSITION_BYTES,
+                bytes.len()
+            )))
+        }?;
+        let mut board = Board::new();
+        for loc in bitboard::squares(board.bitboards.occupied()) {
+            board.set(loc, None);
+        }
+        for i in 0..64 {
+            let code = (bytes[i / 2] >> (4 * (i % 2))) & 0xF;
+            let loc = Location {
+                x: (i % 8) as u8,
+                y: (i / 8) as u8,
+            };
+            let color = if code & 8 == 0 {
+                Color::White
+            } else {
+                Color::Black
+            };
+            let piece = match code & 7 {
+                0 if code == 0 => None,
+                n @ 1..=6 => Piece::new_opt(PIECE_CODES[n as usize - 1], color),
+                _ => return Err(invalid_bytes(format!("bad piece code {} on {}", code, loc))),
+            };
+            board.set(loc, piece);
+        }
+        let () = if bytes[32] >> 5 == 0 {
+            Ok(())
+        } else {
+            Err(invalid_bytes(format!("bad flags {:#04x}", bytes[32])))
+        }?;
+        let flag = |bit: u8| bytes[32] & (1 << bit) != 0;
+        board.turn = if flag(0) { Color::Black } else { Color::White };
+        board.castling = CastlingRights {
+            white_kingside: flag(1),
+            white_queenside: flag(2),
+            black_kingside: flag(3),
+            black_queenside: flag(4),
+        };
+        board.en_passant = match bytes[33] {
+            0xFF => None,
+            n @ 0..=63 => Some(Location { x: n % 8, y: n / 8 }),
+            n => return Err(invalid_bytes(format!("bad en passant square {}", n))),
+        };
+        board.halfmove_clock = u16::from_le_bytes([bytes[34], bytes[35]]) as u32;
+        board.fullmove_number = match u16::from_le_bytes([bytes[36], bytes[37]]) {
+            0 => Err(invalid_bytes("bad fullmove number 0".to_string())),
+            n => Ok(n as u32),
+        }?;
+        board.hash = board.compute_zobrist();
+        board.validate_position()?;
+        Ok(board)
+    }
+
+    // The same position with the board mirrored top to bottom and the colors
+    // swapped, so anything color-blind should treat the two alike. History is
+    // left behind.
+    #[cfg(test)]
+    pub fn flipped(&self) -> Board {
+        let mirror = |loc: Location| Location {
+            x: loc.x,
+            y: 7 - loc.y,
+        };
+        let mut board = Board::new();
+        for loc in bitboard::squares(board.bitboards.occupied()) {
+            board.set(loc, None);
+        }
+        for loc in bitboard::squares(self.bitboards.occupied()) {
+            let piece = self.get(loc).unwrap();
+            board.set(
+                mirror(loc),
+                piece::Piece::new_opt(piece.tpe, piece.color.opposite()),
+            );
+        }
+        board.turn = self.turn.opposite();
+        board.castling = CastlingRights {
+            white_kingside: self.castling.black_kingside,
+            white_queenside: self.castling.black_queenside,
+            black_kingside: self.castling.white_kingside,
+            black_queenside: self.castling.white_queenside,
+        };
+        board.en_passant = self.en_passant.map(mirror);
+        board.halfmove_clock = self.halfmove_clock;
+        board.fullmove_number = self.fullmove_number;
+        board.hash = board.compute_zobrist();
+        board
+    }
+
+    // Lists every problem at once so the whole position can be fixed in one go
+    pub fn validate_position(&self) -> Result<(), Error> {
+        use piece::{Color, Type};
+        let mut problems = Vec::new();
+        for &color in [Color::White, Color::Black].iter() {
+            let kings = self.piece_count(color, Type::King);
+            if kings != 1 {
+                problems.push(format!("{} has {} kings", color.name(), kings));
+            }
+        }
+        for &y in [0, 7].iter() {
+            for x in 0..8 {
+                if let Some(piece) = self.get(Location { x, y }) {
+                    if piece.tpe == Type::Pawn {
+                        problems.push(format!("{} on {}", piece.name(), Location { x, y }));
+                    }
+                }
+            }
+        }
+        for &color in [Color::White, Color::Black].iter() {
+            let rights = [
+                ("kingside", self.castling.kingside(color), 7),
+                ("queenside", self.castling.queenside(color), 0),
+            ];
+            for &(side, allowed, rook_x) in rights.iter() {
+                if allowed && !self.castling_home(color, rook_x) {
+                    problems.push(format!(
+                        "{} cannot castle {} without its king and rook at home",
+                        color.name(),
+                        side
+                    ));
+                }
+            }
+        }
+        if let Some(target) = self.en_passant {
+            // The pawn that just moved two squares sits in front of the target
+            let (rank, pawn_y, from_y) = match self.turn {
+                Color::White => (5, 4, 6),
+                Color::Black => (2, 3, 1),
+            };
+            let pawn = piece::Piece::new_opt(Type::Pawn, self.turn.opposite());
+            if target.y != rank
+                || self.get(Location {
+                    y: pawn_y,
+                    ..target
+                }) != pawn
+                || self.get(target).is_some()
+                || self
+                    .get(Location {
+                        y: from_y,
+                        ..target
+                    })
+                    .is_some()
+            {
+                problems.push(format!(
+                    "en passant target {} does not follow a double pawn push",
+                    target
+                ));
+            }
+        }
+        if self.is_in_check(self.turn.opposite()) {
+            problems.push(format!(
+                "{} is in check but it is {} to move",
+                self.turn.opposite().name(),
+                self.turn.name()
+            ));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorCode::InvalidPosition,
+                format!("Invalid position: {}", problems.join(", ")),
+            ))
+        }
+    }
+
+    pub fn resign(&mut self, color: piece::Color) -> Result<(), Error> {
+        self.ensure_in_progress()?;
+        self.result = Some(GameResult::Resigned(color));
+        self.sync_clock();
+        Ok(())
+    }
+
+    pub fn offer_draw(&mut self, color: piece::Color) -> Result<(), Error> {
+        self.ensure_in_progress()?;
+        self.draw_offer = Some(color);
+        Ok(())
+    }
+
+    pub fn accept_draw(&mut self, color: piece::Color) -> Result<(), Error> {
+        self.ensure_in_progress()?;
+        let () = match self.draw_offer {
+            Some(offered_by) if offered_by != color => Ok(()),
+            Some(_) => Err(Error::new(
+                ErrorCode::OwnDrawOffer,
+                format!("Cannot accept own draw offer: {} offered it", color.name()),
+            )),
+            None => Err(Error::new(
+                ErrorCode::NoDrawOffer,
+                "No draw offer to accept".to_string(),
+            )),
+        }?;
+        self.draw_offer = None;
+        self.result = Some(GameResult::Draw(DrawReason::Agreement));
+        self.sync_clock();
+        Ok(())
+    }
+
+    // Only the side that just moved can ask, and only for that one move
+    pub fn request_takeback(&mut self, color: piece::Color) -> Result<(), Error> {
+        self.ensure_in_progress()?;
+        let () = match self.last_move() {
+            None => Err(Error::new(
+                ErrorCode::NothingToUndo,
+                "No moves to undo".to_string(),
+            )),
+            Some(_) if color == self.turn => Err(Error::new(
+                ErrorCode::InvalidTakeback,
+                format!(
+                    "Cannot request takeback: {} did not make the last move",
+                    color.name()
+                ),
+            )),
+            Some(_) => Ok(()),
+        }?;
+        self.takeback_request = Some(color);
+        Ok(())
+    }
+
+    pub fn approve_takeback(&mut self, color: piece::Color) -> Result<(), Error> {
+        self.ensure_in_progress()?;
+        let () = match self.takeback_request {
+            Some(requested_by) if requested_by != color => Ok(()),
+            Some(_) => Err(Error::new(
+                ErrorCode::OwnTakebackRequest,
+                format!(
+                    "Cannot approve own takeback request: {} asked for it",
+                    color.name()
+                ),
+            )),
+            None => Err(Error::new(
+                ErrorCode::NoTakebackRequest,
+                "No takeback request to approve".to_string(),
+            )),
+        }?;
+        self.undo()
+    }
+
+    pub fn status(&self) -> Status {
+        match self.result {
+            Some(GameResult::Resigned(color)) => return Status::Resigned(color),
+            Some(GameResult::TimeForfeit(color)) => return Status::TimeForfeit(color),
+            Some(GameResult::Draw(reason)) => return Status::Draw(reason),
+            None => {}
+        }
+        if self.setup {
+            return Status::Ongoing;
+        }
+        let no_legal_moves = !self.has_legal_moves();
+        match (no_legal_moves, self.is_in_check(self.turn)) {
+            (false, _) if self.repetitions() >= 5 => Status::Draw(DrawReason::FivefoldRepetition),
+            (false, _) if self.halfmove_clock >= 150 => {
+                Status::Draw(DrawReason::SeventyFiveMoveRule)
+            }
+            (false, _) if self.insufficient_material() => {
+                Status::Draw(DrawReason::InsufficientMaterial)
+            }
+            (false, _) if self.is_dead_position() => Status::Draw(DrawReason::DeadPosition),
+            (false, _) => Status::Ongoing,
+            (true, true) => Status::Checkmate,
+            (true, false) => Status::Stalemate,
+        }
+    }
+
+    // Times the current position has occurred, found by replaying the hashes kept in
+    // the history rather than a running count, so undo needs no bookkeeping. Only
+    // positions since the last capture or pawn move with the same side to move can match.
+    pub fn reversible_hashes(&self) -> Vec<u64> {
+        let mut hashes: Vec<u64> = self
+            .history
+            .iter()
+            .rev()
+            .take(self.halfmove_clock as usize)
+            .map(|entry| entry.hash)
+            .collect();
+        hashes.reverse();
+        hashes
+    }
+
+    pub fn repetitions(&self) -> usize {
+        let earlier = self
+            .history
+            .iter()
+            .rev()
+            .take(self.halfmove_clock as usize)
+            .skip(1)
+            .step_by(2)
+            .filter(|entry| entry.hash == self.hash)
+            .count();
+        earlier + 1
+    }
+
+    // Threefold and fifty-move draws must be claimed; the fivefold and
+    // seventy-five move limits end the game on their own in status()
+    pub fn claim_draw(&mut self) -> Result<(), Error> {
+        self.ensure_in_progress()?;
+        let reason = match self.claimable_draw() {
+            Some(reason) => Ok(reason),
+            None => Err(Error::new(
+                ErrorCode::NoDrawToClaim,
+                format!(
+                    "No draw to claim: position occurred {} times and {} halfmoves since the last capture or pawn move",
+                    self.repetitions(),
+                    self.halfmove_clock
+                ),
+            )),
+        }?;
+        self.draw_offer = None;
+        self.result = Some(GameResult::Draw(reason));
+        self.sync_clock();
+        Ok(())
+    }
+
+    fn claimable_draw(&self) -> Option<DrawReason> {
+        if self.repetitions() >= 3 {
+            Some(DrawReason::ThreefoldRepetition)
+        } else if self.halfmove_clock >= 100 {
+            Some(DrawReason::FiftyMoveRule)
+        } else {
+            None
+        }
+    }
+
+    // Whether `color` could mate by any series of legal moves, for FIDE's timeout rule.
+    // A bare king never can, and a lone knight or bishop can only when the defender
+    // has pieces of its own to block in the king; anything more is assumed to be
+    // able to, short of the whole board being insufficient.
+    pub fn can_mate(&self, color: piece::Color) -> bool {
+        use piece::Type;
+        if self.insufficient_material() {
+            return false;
+        }
+        // Everything but the king
+        let count = |color| self.bitboards.color(color).count_ones() - 1;
+        let minors = self.piece_count(color, Type::Knight) + self.piece_count(color, Type::Bishop);
+        match count(color) {
+            0 => false,
+            1 if minors == 1 => count(color.opposite()) > 0,
+            _ => true,
+        }
+    }
+
+    // The FIDE rule: a draw only when no sequence of legal moves could mate, which
+    // leaves bare kings, a single minor piece, or only bishops that all share a
+    // square color. K+N+N v K, K+B+N and opposite-color bishops play on, since the
+    // defender can still blunder into mate, even though none of them can force it.
+    pub fn insufficient_material(&self) -> bool {
+        use piece::{Color, Type};
+        let count = |tpe| self.piece_count(Color::White, tpe) + self.piece_count(Color::Black, tpe);
+        if count(Type::Pawn) + count(Type::Rook) + count(Type::Queen) > 0 {
+            return false;
+        }
+        if count(Type::Knight) + count(Type::Bishop) <= 1 {
+            return true;
+        }
+        let mut bishop_squares = Vec::new();
+        for y in 0..8 {
+            for x in 0..8 {
+                if let Some(piece) = self.get(Location { x, y }) {
+                    if piece.tpe == Type::Bishop {
+                        bishop_squares.push(Location { x, y }.is_dark());
+                    }
+                }
+            }
+        }
+        count(Type::Knight) == 0 && bishop_squares.iter().all(|&dark| dark == bishop_squares[0])
+    }
+
+    // Only the locked-pawn case: kings and pawns, every pawn blocked head-on by an
+    // enemy pawn, no pawn captures, and neither king able to walk to an enemy pawn
+    // it could take. Then no pawn can ever move and no check is possible.
+    pub fn is_dead_position(&self) -> bool {
+        use piece::{Color, Type};
+        let pawns = |color| self.bitboards.pieces(Type::Pawn, color);
+        let kings = self.bitboards.pieces(Type::King, Color::White)
+            | self.bitboards.pieces(Type::King, Color::Black);
+        let all_pawns = pawns(Color::White) | pawns(Color::Black);
+        if self.bitboards.occupied() != all_pawns | kings
+            || pawns(Color::White) << 8 != pawns(Color::Black)
+            || self.en_passant.is_some()
+        {
+            return false;
+        }
+        for &color in [Color::White, Color::Black].iter() {
+            let theirs = pawns(color.opposite());
+            let attacks = |from: u64, color| {
+                bitboard::squares(from).fold(0, |bb, loc| bb | bitboard::pawn_attacks(color, loc))
+            };
+            if attacks(pawns(color), color) & theirs != 0 {
+                return false;
+            }
+            // Flood fill the squares the king can walk to without stepping into a pawn attack
+            let guarded = attacks(theirs, color.opposite());
+            let king = self.bitboards.pieces(Type::King, color);
+            if king & guarded != 0 {
+                return false;
+            }
+            let neighbours = |bb: u64| {
+                bitboard::squares(bb).fold(0, |acc, loc| acc | bitboard::king_attacks(loc))
+            };
+            let mut reach = king;
+            loop {
+                let next = reach | (neighbours(reach) & !(all_pawns | guarded));
+                if next == reach {
+                    break;
+                }
+                reach = next;
+            }
+            if neighbours(reach) & theirs & !guarded != 0 {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn piece_count(&self, color: piece::Color, tpe: piece::Type) -> u8 {
+        self.bitboards.pieces(tpe, color).count_ones() as u8
+    }
+
+    // Material comes from the bitboard counts and the piece-square part is kept
+    // incrementally, so only mobility needs any work here
+    pub fn evaluate_terms(&self, config: &EvalConfig) -> EvalTerms {
+        use piece::{Color, Type};
+        let mut material = 0;
+        for &tpe in [
+            Type::Pawn,
+            Type::Knight,
+            Type::Bishop,
+            Type::Rook,
+            Type::Queen,
+        ]
+        .iter()
+        {
+            let count = self.piece_count(Color::White, tpe) as i32
+                - self.piece_count(Color::Black, tpe) as i32;
+            material += config.piece_value(tpe) * count;
+        }
+        let mobility = self.pseudo_legal_moves(Color::White).len() as i32
+            - self.pseudo_legal_moves(Color::Black).len() as i32;
+        EvalTerms {
+            material,
+            piece_square: self.positional,
+            mobility: config.mobility * mobility,
+            mop_up: self.mop_up(config),
+        }
+    }
+
+    // Against a lone king, material alone gives the stronger side nothing to aim
+    // for, so drive the king to the edge, bring our own king up and take away its
+    // squares
+    fn mop_up(&self, config: &EvalConfig) -> i32 {
+        use piece::{Color, Type};
+        let mut score = 0;
+        for &(strong, sign) in [(Color::White, 1), (Color::Black, -1)].iter() {
+            let weak = strong.opposite();
+            if self.bitboards.color(weak).count_ones() != 1 {
+                continue;
+            }
+            let material: i32 = bitboard::TYPES
+                .iter()
+                .map(|&tpe| config.piece_value(tpe) * self.piece_count(strong, tpe) as i32)
+                .sum();
+            // Minor pieces alone can't force mate
+            if material < config.rook {
+                continue;
+            }
+            let king = |color| bitboard::squares(self.bitboards.pieces(Type::King, color)).next();
+            let (weak_king, strong_king) = match (king(weak), king(strong)) {
+                (Some(weak_king), Some(strong_king)) => (weak_king, strong_king),
+                _ => continue,
+            };
+            let from_center = |v: u8| (3 - v as i32).max(v as i32 - 4);
+            let edge = from_center(weak_king.x) + from_center(weak_king.y);
+            let distance = weak_king.distance_manhattan(strong_king) as i32;
+            let escapes = (bitboard::king_attacks(weak_king) & !self.attacked_squares(strong))
+                .count_ones() as i32;
+            score += sign
+                * (MOP_UP_EDGE * edge
+                    + MOP_UP_KINGS * (14 - distance)
+                    + MOP_UP_ESCAPES * (8 - escapes));
+        }
+        score
+    }
+
+    // Squares the king of `color` could walk to if the other side stood still,
+    // which is the box a lone king is being squeezed into
+    fn king_room(&self, color: piece::Color) -> u32 {
+        let king = match bitboard::squares(self.bitboards.pieces(piece::Type::King, color)).next() {
+            None => return 0,
+            Some(king) => king,
+        };
+        // The king doesn't shield the squares behind it from the pieces it steps away from
+        let occupied = self.bitboards.occupied() & !bitboard::bit(king);
+        let mut attacked = 0;
+        for from in bitboard::squares(self.bitboards.color(color.opposite())) {
+            attacked |= bitboard::attacks(self.get(from).unwrap(), from, occupied);
+        }
+        let mut room = bitboard::bit(king);
+        loop {
+            let reach = bitboard::squares(room).fold(room, |reach, sq| {
+                reach | (bitboard::king_attacks(sq) & !attacked)
+            });
+            if reach == room {
+                return room.count_ones();
+            }
+            room = reach;
+        }
+    }
+
+    // The textbook way to mate with a lone queen or rook: look a few moves ahead
+    // for the line that leaves the lone king the smallest box with our king
+    // closest, never stalemating or leaving the piece hanging, and take a mate as
+    // soon as one is in reach. None outside K+Q vs K and K+R vs K with the
+    // stronger side to move.
+    pub fn endgame_move(&self) -> Option<Move> {
+        use piece::Type;
+        let (strong, weak) = (self.turn, self.turn.opposite());
+        let heavy = self.piece_count(strong, Type::Queen) + self.piece_count(strong, Type::Rook);
+        if self.bitboards.color(weak).count_ones() != 1
+            || self.bitboards.color(strong).count_ones() != 2
+            || heavy != 1
+            || self.ensure_in_progress().is_err()
+            || self.pending_promotion.is_some()
+        {
+            return None;
+        }
+        let seen = self.reversible_hashes();
+        let mut board = self.without_history();
+        let mut best: Option<((i32, bool), Move)> = None;
+        for mv in board.legal_moves() {
+            let undo = board.make_move(mv);
+            // Moves below the best so far only need to be shown to be worse, and a
+            // repeat only wins when nothing else is as good
+            let alpha = best
+                .as_ref()
+                .map_or(-ENDGAME_INFINITY, |((score, _), _)| score - 1);
+            let key = (
+                board.squeeze(strong, ENDGAME_PLIES - 1, alpha, ENDGAME_INFINITY),
+                !seen.contains(&board.zobrist()),
+            );
+            board.unmake_move(mv, undo);
+            if best.as_ref().is_none_or(|(best, _)| key > *best) {
+                best = Some((key, mv));
+            }
+        }
+        best.map(|(_, mv)| mv)
+    }
+
+    // Alpha-beta for endgame_move, scored from the stronger side's point of view
+    fn squeeze(&mut self, strong: piece::Color, plies: u8, mut alpha: i32, mut beta: i32) -> i32 {
+        use piece::Type;
+        let weak = strong.opposite();
+        let moves = self.legal_moves();
+        if moves.is_empty() {
+            return match (self.turn == weak, self.is_in_check(self.turn)) {
+                // Sooner mates have more plies left
+                (true, true) => ENDGAME_MATE + plies as i32,
+                _ => -ENDGAME_MATE,
+            };
+        }
+        if self.bitboards.color(strong).count_ones() < 2 {
+            return -ENDGAME_MATE;
+        }
+        if plies == 0 {
+            let king = |color| {
+                bitboard::squares(self.bitboards.pieces(Type::King, color))
+                    .next()
+                    .unwrap()
+            };
+            let (ours, theirs) = (king(strong), king(weak));
+            let distance = ours.distance_chebyshev(theirs) as i32;
+            return -(ENDGAME_ROOM * self.king_room(weak) as i32 + distance);
+        }
+        let maximizing = self.turn == strong;
+        for mv in moves {
+            let undo = self.make_move(mv);
+            let score = self.squeeze(strong, plies - 1, alpha, beta);
+            self.unmake_move(mv, undo);
+            if maximizing {
+                alpha = alpha.max(score);
+            } else {
+                beta = beta.min(score);
+            }
+            if alpha >= beta {
+                break;
+            }
+        }
+        if maximizing {
+            alpha
+        } else {
+            beta
+        }
+    }
+
+    pub fn evaluate(&self, config: &EvalConfig) -> i32 {
+        self.evaluate_terms(config).total()
+    }
+}
+
+// Centipawns, positive when White is better
+#[derive(Debug, Copy, Clone, Serialize)]
+struct EvalTerms {
+    material: i32,
+    piece_square: i32,
+    mobility: i32,
+    mop_up: i32,
+}
+
+impl EvalTerms {
+    pub fn total(&self) -> i32 {
+        self.material + self.piece_square + self.mobility + self.mop_up
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct EvalConfig {
+    pawn: i32,
+    knight: i32,
+    bishop: i32,
+    rook: i32,
+    queen: i32,
+    mobility: i32,
+}
+
+impl EvalConfig {
+    pub fn piece_value(&self, tpe: piece::Type) -> i32 {
+        use piece::Type;
+        match tpe {
+            Type::Pawn => self.pawn,
+            Type::Knight => self.knight,
+            Type::Bishop => self.bishop,
+            Type::Rook => self.rook,
+            Type::Queen => self.queen,
+            Type::King => 0,
+        }
+    }
+}
+
+impl Default for EvalConfig {
+    fn default() -> EvalConfig {
+        EvalConfig {
+            pawn: 100,
+            knight: 320,
+            bishop: 330,
+            rook: 500,
+            queen: 900,
+            mobility: 5,
+        }
+    }
+}
+
+// endgame_move looks this many plies ahead, ending on the lone king's reply. A
+// square of room for the lone king counts as much as ENDGAME_ROOM steps of
+// distance between the kings.
+const ENDGAME_PLIES: u8 = 6;
+const ENDGAME_ROOM: i32 = 4;
+const ENDGAME_MATE: i32 = 10_000;
+const ENDGAME_INFINITY: i32 = 1_000_000;
+
+// Centipawns per step of the lone king's distance from the center, per step the
+// kings are closer and per square taken from the lone king
+const MOP_UP_EDGE: i32 = 20;
+const MOP_UP_KINGS: i32 = 25;
+const MOP_UP_ESCAPES: i32 = 10;
+
+const PROMOTIONS: [piece::Type; 4] = [
+    piece::Type::Queen,
+    piece::Type::Rook,
+    piece::Type::Bishop,
+    piece::Type::Knight,
+];
+
+// Plain ASCII grid with White at the bottom, for logs and the terminal
+impl fmt::Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for y in (0..8).rev() {
+            write!(f, "{}", y + 1)?;
+            for x in 0..8 {
+                let cell = self
+                    .get(Location { x, y })
+                    .map_or('.', |piece| piece.fen_letter());
+                write!(f, " {}", cell)?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "  a b c d e f g h")
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+struct Move {
+    from: Location,
+    to: Location,
+    promote: Option<piece::Type>,
+}
+
+// UCI notation such as e7e8q
+impl fmt::Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.from, self.to)?;
+        if let Some(tpe) = self.promote {
+            write!(f, "{}", tpe.letter().to_ascii_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+// Only checks the notation; whether the move is legal is up to the board
+impl FromStr for Move {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Move, Error> {
+        let invalid = || Error::new(ErrorCode::InvalidUci, format!("Invalid UCI move {}", s));
+        if !s.is_ascii() || s.len() < 4 || s.len() > 5 {
+            return Err(invalid());
+        }
+        let from = s[0..2].parse::<Location>()?;
+        let to = s[2..4].parse::<Location>()?;
+        let promote = match s[4..].chars().next() {
+            None => None,
+            Some(c) if c.is_ascii_lowercase() => Some(promotion_from_letter(c)?),
+            Some(_) => return Err(invalid()),
+        };
+        Ok(Move { from, to, promote })
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Undo {
+    captured: Option<piece::Piece>,
+    castling: CastlingRights,
+    en_passant: Option<Location>,
+    halfmove_clock: u32,
+}
+
+fn push_moves(moves: &mut Vec<Move>, piece: piece::Piece, from: Location, to: Location) {
+    if piece.promotes_at(to) {
+        for &tpe in PROMOTIONS.iter() {
+            moves.push(Move {
+                from,
+                to,
+                promote: Some(tpe),
+            });
+        }
+    } else {
+        moves.push(Move {
+            from,
+            to,
+            promote: None,
+        });
+    }
+}
+
+// How often pseudo_legal_moves missed its cache on this thread, for the tests
+// that check what the caches save
+#[cfg(test)]
+thread_local! {
+    static GENERATED_MOVES: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+fn same_moves(a: &[Move], b: &[Move]) -> bool {
+    let (mut a, mut b) = (a.to_vec(), b.to_vec());
+    a.sort();
+    b.sort();
+    a == b
+}
+
+fn invalid_bytes(message: String) -> Error {
+    Error::new(
+        ErrorCode::InvalidBody,
+        format!("Invalid bytes: {}", message),
+    )
+}
+
+// Every entry point (query arg, UCI, SAN) resolves promotions through this or
+// through the legal move list, and validate_move rejects anything else
+fn promotion_from_letter(c: char) -> Result<piece::Type, Error> {
+    match piece::Type::from_letter(c) {
+        Some(tpe) if PROMOTIONS.contains(&tpe) => Ok(tpe),
+        _ => Err(Error::new(
+            ErrorCode::InvalidPromotion,
+            format!("Invalid promotion piece {}", c),
+        )),
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        None => String::new(),
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+    }
+}
+
+fn en_passant_capture(piece: piece::Piece, mv: Move, target: Option<Location>) -> Option<Location> {
+    if piece.tpe == piece::Type::Pawn && Some(mv.to) == target && mv.from.x != mv.to.x {
+        Some(Location {
+            x: mv.to.x,
+            y: mv.from.y,
+        })
+    } else {
+        None
+    }
+}
+
+fn castling_rook(piece: piece::Piece, mv: Move) -> Option<(Location, Location)> {
+    let y = mv.from.y;
+    match (piece.tpe, mv.from.x, mv.to.x) {
+        (piece::Type::King, 4, 6) => Some((Location { x: 7, y }, Location { x: 5, y })),
+        (piece::Type::King, 4, 2) => Some((Location { x: 0, y }, Location { x: 3, y })),
+        _ => None,
+    }
+}
+
+// Strips tags, comments, variations, NAGs, move numbers and results from PGN
+fn pgn_movetext(pgn: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut in_comment = false;
+    let mut variation_depth = 0;
+    for line in pgn.lines() {
+        if !in_comment && line.trim_start().starts_with('[') {
+            continue;
+        }
+        for c in line.chars() {
+            if in_comment {
+                in_comment = c != '}';
+                continue;
+            }
+            if c == ';' {
+                break;
+            }
+            match c {
+                '{' => in_comment = true,
+                '(' => variation_depth += 1,
+                ')' => variation_depth = 0.max(variation_depth - 1),
+                _ if variation_depth > 0 => {}
+                _ if !c.is_whitespace() => token.push(c),
+                _ => {}
+            }
+            if variation_depth > 0 || in_comment || c.is_whitespace() {
+                tokens.push(std::mem::take(&mut token));
+            }
+        }
+        tokens.push(std::mem::take(&mut token));
+    }
+    tokens
+        .into_iter()
+        .map(|token| match token.rfind('.') {
+            Some(idx) => token[idx + 1..].to_string(),
+            None => token,
+        })
+        .filter(|token| {
+            !token.is_empty()
+                && !token.starts_with('$')
+                && !["1-0", "0-1", "1/2-1/2", "*"].contains(&token.as_str())
+        })
+        .collect()
+}
+
+// "base+increment" in seconds, as in the PGN TimeControl tag
+fn parse_time_control(raw: &str) -> Option<(Duration, Duration)> {
+    let (base, increment) = raw.split_once('+')?;
+    let secs = |raw: &str| Duration::try_from_secs_f64(raw.parse::<f64>().ok()?).ok();
+    Some((secs(base)?, secs(increment)?))
+}
+
+// h:mm:ss.mmm, the clock tag form
+fn format_clock(time: Duration) -> String {
+    let ms = time.as_millis();
+    format!(
+        "{}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+fn parse_clock(raw: &str) -> Option<Duration> {
+    let mut parts = raw.split(':');
+    let (hours, minutes, seconds) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let minutes = hours.parse::<u64>().ok()? * 60 + minutes.parse::<u64>().ok()?;
+    let seconds = Duration::try_from_secs_f64(seconds.parse::<f64>().ok()?).ok()?;
+    Some(Duration::from_secs(minutes * 60) + seconds)
+}
+
+// Written to a sibling file first so a crash mid-write keeps the previous save
+fn write_save(path: &Path, contents: &str) -> Result<(), String> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents)
+        .and_then(|()| fs::rename(&tmp, path))
+        .map_err(|e| format!("Could not save to {}: {}", path.display(), e))
+}
+
+fn pgn_tag(pgn: &str, name: &str) -> Option<String> {
+    pgn.lines().find_map(|line| {
+        let inner = line.trim().strip_prefix('[')?.strip_suffix(']')?;
+        let (tag, value) = inner.split_once(' ')?;
+        if tag != name {
+            return None;
+        }
+        let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+        Some(value.to_string())
+    })
+}
+
+#[derive(Debug, Copy, Clone)]
+struct HistoryEntry {
+    mv: Move,
+    undo: Undo,
+    // Position before the move, for repetition detection
+    hash: u64,
+}
+
+mod search {
+    use std::cmp::Reverse;
+    use std::time::{Duration, Instant};
+
+    use serde::Serialize;
+
+    use super::piece::{Color, Type};
+    use super::random::{Rng, SplitMix64};
+    use super::{Board, EvalConfig, Move};
+
+    const INFINITY: i32 = 1_000_000;
+    const MATE: i32 = 100_000;
+    const CLOCK_CHECK_INTERVAL: u64 = 1024;
+    const TABLE_SIZE: usize = 1 << 16;
+
+    // Hard caps so no request can tie up the server, whatever it asks for
+    pub const MAX_DEPTH: u8 = 12;
+    pub const MAX_TIME: Duration = Duration::from_secs(10);
+    pub const MAX_NODES: u64 = 5_000_000;
+
+    // Which server cap, if any, cut a search short
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Limit {
+        Depth,
+        Time,
+        Nodes,
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Bound {
+        Exact,
+        Lower,
+        Upper,
+    }
+
+    #[derive(Debug, Copy, Clone)]
+    struct Entry {
+        key: u64,
+        depth: u8,
+        score: i32,
+        bound: Bound,
+        best: Option<Move>,
+    }
+
+    // A table of size 0 remembers nothing
+    struct TranspositionTable {
+        entries: Vec<Option<Entry>>,
+    }
+
+    impl TranspositionTable {
+        fn new(size: usize) -> TranspositionTable {
+            TranspositionTable {
+                entries: vec![None; size],
+            }
+        }
+
+        fn index(&self, key: u64) -> usize {
+            (key % self.entries.len() as u64) as usize
+        }
+
+        fn probe(&self, key: u64) -> Option<Entry> {
+            if self.entries.is_empty() {
+                return None;
+            }
+            match self.entries[self.index(key)] {
+                Some(entry) if entry.key == key => Some(entry),
+                _ => None,
+            }
+        }
+
+        fn store(&mut self, entry: Entry) {
+            if self.entries.is_empty() {
+                return;
+            }
+            // Keep the deeper result for the same position, otherwise always replace
+            let idx = self.index(entry.key);
+            match self.entries[idx] {
+                Some(old) if old.key == entry.key && old.depth > entry.depth => {}
+                _ => self.entries[idx] = Some(entry),
+            }
+        }
+    }
+
+    pub struct Search {
+        tt: TranspositionTable,
+        config: EvalConfig,
+        quiescence: bool,
+        // Off only to measure what ordering saves
+        ordered: bool,
+        noise: i32,
+        rng: SplitMix64,
+        deadline: Option<Instant>,
+        // Whether the deadline is MAX_TIME rather than the caller's own budget
+        time_capped: bool,
+        node_limit: Option<u64>,
+        stopped: bool,
+        limit: Option<Limit>,
+        nodes: u64,
+        // Root moves left out of the search, so the next best can be found
+        excluded: Vec<Move>,
+        // Positions from the last irreversible game move down to the current node
+        path: Vec<u64>,
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct SearchParams {
+        pub depth: u8,
+        pub quiescence: bool,
+        // Each root move's score is shifted by up to this many centipawns either way
+        pub noise: i32,
+    }
+
+    // Lower levels search shallower, skip quiescence and blunder more through noise.
+    // Each level is at least as strong as the one below in every respect.
+    pub fn params_for_level(level: u8) -> SearchParams {
+        let (depth, quiescence, noise) = match level {
+            0 | 1 => (1, false, 300),
+            2 => (1, false, 150),
+            3 => (2, false, 100),
+            4 => (2, true, 60),
+            5 => (3, true, 40),
+            6 => (3, true, 20),
+            7 => (4, true, 10),
+            8 => (4, true, 5),
+            9 => (5, true, 5),
+            _ => (5, true, 0),
+        };
+        SearchParams {
+            depth,
+            quiescence,
+            noise,
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct SearchResult {
+        pub best: Move,
+        // Centipawns from the side to move's point of view
+        pub score: i32,
+        pub pv: Vec<Move>,
+        pub depth: u8,
+        pub nodes: u64,
+        pub limit: Option<Limit>,
+    }
+
+    impl SearchResult {
+        // A move found without searching, scored by the static evaluation.
+        // Nothing was searched, so depth and nodes are 0.
+        pub fn technique(board: &Board, best: Move) -> SearchResult {
+            let score = board.evaluate(&EvalConfig::default());
+            SearchResult {
+                best,
+                score: match board.turn {
+                    Color::White => score,
+                    Color::Black => -score,
+                },
+                pv: vec![best],
+                depth: 0,
+                nodes: 0,
+                limit: None,
+            }
+        }
+    }
+
+    impl Search {
+        pub fn new(config: EvalConfig) -> Search {
+            Search {
+                tt: TranspositionTable::new(TABLE_SIZE),
+                config,
+                quiescence: true,
+                ordered: true,
+                noise: 0,
+                rng: SplitMix64::new(0),
+                deadline: None,
+                time_capped: false,
+                node_limit: None,
+                stopped: false,
+                limit: None,
+                nodes: 0,
+                excluded: Vec::new(),
+                path: Vec::new(),
+            }
+        }
+
+        // For measuring what the table saves
+        #[cfg(test)]
+        pub fn without_table(self) -> Search {
+            Search {
+                tt: TranspositionTable::new(0),
+                ..self
+            }
+        }
+
+        #[cfg(test)]
+        pub fn without_ordering(self) -> Search {
+            Search {
+                ordered: false,
+                ..self
+            }
+        }
+
+        // The seed only matters when params add noise; the same seed replays the same choices
+        pub fn with_params(config: EvalConfig, params: SearchParams, seed: u64) -> Search {
+            Search {
+                quiescence: params.quiescence,
+                noise: params.noise,
+                rng: SplitMix64::new(seed),
+                ..Search::new(config)
+            }
+        }
+
+        // No budget, or one past MAX_TIME, runs to MAX_TIME and counts hitting it as a cap
+        fn deadline_for(budget: Option<Duration>) -> (Instant, bool) {
+            let capped = budget.is_none_or(|budget| budget >= MAX_TIME);
+            let deadline = Instant::now() + budget.map_or(MAX_TIME, |budget| budget.min(MAX_TIME));
+            (deadline, capped)
+        }
+
+        pub fn best_move(
+            &mut self,
+            board: &mut Board,
+            max_depth: u8,
+            budget: Option<Duration>,
+        ) -> Option<SearchResult> {
+            let (deadline, time_capped) = Search::deadline_for(budget);
+            self.search_until(board, max_depth, deadline, time_capped)
+        }
+
+        fn search_until(
+            &mut self,
+            board: &mut Board,
+            max_depth: u8,
+            deadline: Instant,
+            time_capped: bool,
+        ) -> Option<SearchResult> {
+            self.time_capped = time_capped;
+            let node_limit = self.nodes + MAX_NODES;
+            self.stopped = false;
+            self.limit = None;
+            self.path = board.reversible_hashes();
+            self.path.push(board.zobrist());
+            let mut result: Option<SearchResult> = None;
+            for depth in 1..=max_depth.clamp(1, MAX_DEPTH) {
+                let first = result.as_ref().map(|result| result.best);
+                let (best, score) = match self.search_root(board, depth, first) {
+                    None => break,
+                    Some(best) => best,
+                };
+                let pv = self.principal_variation(board, best, depth);
+                result = Some(SearchResult {
+                    best,
+                    score,
+                    pv,
+                    depth,
+                    nodes: self.nodes,
+                    limit: None,
+                });
+                // Only arm the limits once there is a completed iteration to fall back on
+                self.deadline = Some(deadline);
+                self.node_limit = Some(node_limit);
+                if self.out_of_time() {
+                    self.stop(Limit::Time);
+                    break;
+                }
+            }
+            self.deadline = None;
+            self.node_limit = None;
+            let depth_capped = max_depth > MAX_DEPTH
+                && result
+                    .as_ref()
+                    .is_some_and(|result| result.depth == MAX_DEPTH);
+            if self.limit.is_none() && depth_capped {
+                self.limit = Some(Limit::Depth);
+            }
+            result.map(|result| SearchResult {
+                nodes: self.nodes,
+                limit: self.limit,
+                ..result
+            })
+        }
+
+        // The best few root moves, each found by searching again without the ones
+        // before it. Every line gets its own node cap, the time budget is shared.
+        pub fn best_lines(
+            &mut self,
+            board: &mut Board,
+            max_depth: u8,
+            budget: Option<Duration>,
+            lines: usize,
+        ) -> Vec<SearchResult> {
+            let (deadline, time_capped) = Search::deadline_for(budget);
+            let mut results: Vec<SearchResult> = Vec::new();
+            while results.len() < lines {
+                let result = match self.search_until(board, max_depth, deadline, time_capped) {
+                    None => break,
+                    Some(result) => result,
+                };
+                self.excluded.push(result.best);
+                results.push(result);
+            }
+            self.excluded.clear();
+            // Lines cut short by the clock can come out of order
+            results.sort_by_key(|result| -result.score);
+            results
+        }
+
+        // Counts a node and reports whether the search should unwind. The clock is
+        // only read every CLOCK_CHECK_INTERVAL nodes.
+        fn tick(&mut self) -> bool {
+            self.nodes += 1;
+            if !self.stopped {
+                if self.node_limit.is_some_and(|limit| self.nodes >= limit) {
+                    self.stop(Limit::Nodes);
+                } else if self.nodes.is_multiple_of(CLOCK_CHECK_INTERVAL) && self.out_of_time() {
+                    self.stop(Limit::Time);
+                }
+            }
+            self.stopped
+        }
+
+        fn stop(&mut self, limit: Limit) {
+            self.stopped = true;
+            // Running out of the caller's own time budget isn't a cap being hit
+            if limit != Limit::Time || self.time_capped {
+                self.limit = Some(limit);
+            }
+        }
+
+        fn out_of_time(&self) -> bool {
+            self.deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        }
+
+        fn order(&self, board: &Board, moves: &mut [Move], hash_move: Option<Move>) {
+            if self.ordered {
+                order_moves(board, moves, hash_move);
+            }
+        }
+
+        fn search_root(
+            &mut self,
+            board: &mut Board,
+            depth: u8,
+            first: Option<Move>,
+        ) -> Option<(Move, i32)> {
+            let mut moves = board.legal_moves();
+            moves.retain(|mv| !self.excluded.contains(mv));
+            self.order(board, &mut moves, first);
+            let mut best = None;
+            let mut alpha = -INFINITY;
+            let mut best_noisy = -INFINITY;
+            for mv in moves {
+                // Noisy choices need an exact score for every move, not just a bound
+                let window = if self.noise > 0 { -INFINITY } else { alpha };
+                let undo = board.make_move(mv);
+                let score = -self.negamax(board, depth - 1, -INFINITY, -window);
+                board.unmake_move(mv, undo);
+                if self.stopped {
+                    return None;
+                }
+                let noisy = score + self.jitter();
+                if best.is_none() || noisy > best_noisy {
+                    alpha = alpha.max(score);
+                    best_noisy = noisy;
+                    best = Some((mv, score));
+                }
+            }
+            best
+        }
+
+        // Only positions since the last capture or pawn move, with the same side to
+        // move, can come round again
+        fn is_repeat(&self, key: u64, halfmove_clock: u32) -> bool {
+            self.path
+                .iter()
+                .rev()
+                .take(halfmove_clock as usize)
+                .skip(1)
+                .step_by(2)
+                .any(|&earlier| earlier == key)
+        }
+
+        fn jitter(&mut self) -> i32 {
+            if self.noise == 0 {
+                return 0;
+            }
+            let span = 2 * self.noise as u64 + 1;
+            (self.rng.next_u64() % span) as i32 - self.noise
+        }
+
+        fn static_eval(&self, board: &Board) -> i32 {
+            match board.turn {
+                Color::White => board.evaluate(&self.config),
+                Color::Black => -board.evaluate(&self.config),
+            }
+        }
+
+        // Follow the table's best moves from the root. Entries can be overwritten,
+        // so the line may stop short of the searched depth.
+        fn principal_variation(&self, board: &mut Board, best: Move, depth: u8) -> Vec<Move> {
+            let mut pv = vec![best];
+            let mut undos = vec![board.make_move(best)];
+            while pv.len() < depth as usize {
+                let next = match self.tt.probe(board.zobrist()).and_then(|entry| entry.best) {
+                    None => break,
+                    Some(next) => next,
+                };
+                if !board.legal_moves().contains(&next) {
+                    break;
+                }
+                undos.push(board.make_move(next));
+                pv.push(next);
+            }
+            for (&mv, undo) in pv.iter().zip(undos).rev() {
+                board.unmake_move(mv, undo);
+            }
+            pv
+        }
+
+        fn negamax(&mut self, board: &mut Board, depth: u8, mut alpha: i32, mut beta: i32) -> i32 {
+            if self.tick() {
+                return 0;
+            }
+            let key = board.zobrist();
+            // Scoring any repeat as a draw keeps a winning side from shuffling in place
+            if self.is_repeat(key, board.halfmove_clock) {
+                return 0;
+            }
+            let orig_alpha = alpha;
+            let entry = self.tt.probe(key);
+            if let Some(entry) = entry {
+                if entry.depth >= depth {
+                    match entry.bound {
+                        Bound::Exact => return entry.score,
+                        Bound::Lower => alpha = alpha.max(entry.score),
+                        Bound::Upper => beta = beta.min(entry.score),
+                    }
+                    if alpha >= beta {
+                        return entry.score;
+                    }
+                }
+            }
+            if depth == 0 {
+                return if self.quiescence {
+                    self.quiesce(board, alpha, beta)
+                } else if !board.has_legal_moves() {
+                    // A winning evaluation of a stalemate would walk straight into it
+                    if board.is_in_check(board.turn) {
+                        -MATE
+                    } else {
+                        0
+                    }
+                } else {
+                    self.static_eval(board)
+                };
+            }
+            let mut moves = board.legal_moves();
+            if moves.is_empty() {
+                // Prefer the quickest mate by scoring it higher with more depth remaining
+                return if board.is_in_check(board.turn) {
+                    -MATE - depth as i32
+                } else {
+                    0
+                };
+            }
+            self.order(board, &mut moves, entry.and_then(|entry| entry.best));
+            let mut best = -INFINITY;
+            let mut best_move = None;
+            self.path.push(key);
+            for mv in moves {
+                let undo = board.make_move(mv);
+                let score = -self.negamax(board, depth - 1, -beta, -alpha);
+                board.unmake_move(mv, undo);
+                if self.stopped {
+                    break;
+                }
+                if score > best {
+                    best = score;
+                    best_move = Some(mv);
+                }
+                alpha = alpha.max(score);
+                if alpha >= beta {
+                    break;
+                }
+            }
+            self.path.pop();
+            if self.stopped {
+                return 0;
+            }
+            let bound = if best <= orig_alpha {
+                Bound::Upper
+            } else if best >= beta {
+                Bound::Lower
+            } else {
+                Bound::Exact
+            };
+            self.tt.store(Entry {
+                key,
+                depth,
+                score: best,
+                bound,
+                best: best_move,
+            });
+            best
+        }
+
+        // Resolve captures (and any check) at the leaves so the evaluation is
+        // never taken in the middle of an exchange
+        fn quiesce(&mut self, board: &mut Board, mut alpha: i32, beta: i32) -> i32 {
+            if self.tick() {
+                return 0;
+            }
+            let in_check = board.is_in_check(board.turn);
+            let mut moves = board.legal_moves();
+            if moves.is_empty() {
+                return if in_check { -MATE } else { 0 };
+            }
+            if !in_check {
+                let stand_pat = self.static_eval(board);
+                if stand_pat >= beta {
+                    return stand_pat;
+                }
+                alpha = alpha.max(stand_pat);
+                moves.retain(|&mv| board.is_capture(mv) || mv.promote.is_some());
+            }
+            self.order(board, &mut moves, None);
+            let mut best = alpha;
+            for mv in moves {
+                let undo = board.make_move(mv);
+                let score = -self.quiesce(board, -beta, -alpha);
+                board.unmake_move(mv, undo);
+                if self.stopped {
+                    return 0;
+                }
+                best = best.max(score);
+                alpha = alpha.max(score);
+                if alpha >= beta {
+                    break;
+                }
+            }
+            best
+        }
+    }
+
+    fn victim_value(tpe: Type) -> i32 {
+        match tpe {
+            Type::Pawn => 1,
+            Type::Knight => 2,
+            Type::Bishop => 3,
+            Type::Rook => 4,
+            Type::Queen => 5,
+            Type::King => 6,
+        }
+    }
+
+    // Hash move first, then captures by most valuable victim / least valuable attacker.
+    // A promotion gains the new piece, so it ranks as if capturing one.
+    pub fn order_moves(board: &Board, moves: &mut [Move], hash_move: Option<Move>) {
+        moves.sort_by_cached_key(|&mv| {
+            if Some(mv) == hash_move {
+                return Reverse(i32::MAX);
+            }
+            let attacker = board.get(mv.from).unwrap();
+            let gain = board
+                .captured(mv)
+                .map_or(0, |(_, victim)| victim_value(victim.tpe))
+                + mv.promote.map_or(0, victim_value);
+            if gain == 0 {
+                Reverse(0)
+            } else {
+                Reverse(100 + 10 * gain - victim_value(attacker.tpe))
+            }
+        });
+    }
+}
+
+fn cell_as_str(cell: &Option<piece::Piece>) -> String {
+    match cell {
+        None => "".to_string(),
+        Some(piece) => piece.name(),
+    }
+}
+
+fn board_as_str(board: &Board, perspective: piece::Color) -> String {
+    let cells: Vec<String> = board
+        .cells_oriented(perspective)
+        .iter()
+        .map(|(_, cell)| cell_as_str(cell))
+        .collect();
+    cells.join(",")
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query_args: HashMap<String, String>,
+    // Keyed by lowercased name
+    headers: HashMap<String, String>,
+    // Raw, since /load_bytes takes a binary position
+    body: Vec<u8>,
+}
+
+// Header lines after the request line. Names are case-insensitive, so they are
+// lowercased; a repeated header is joined with commas as RFC 9110 allows.
+fn parse_headers(head: &str) -> HashMap<String, String> {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for line in head.lines().skip(1) {
+        let (name, value) = match line.split_once(':') {
+            None => continue,
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+        };
+        headers
+            .entry(name)
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    headers
+}
+
+// Reads the head up to the blank line and then the body it announces. None
+// means the client went away, whether by a read error or by closing the
+// connection before sending anything.
+fn read_request(mut stream: &TcpStream) -> Option<(String, Vec<u8>)> {
+    let mut buffer = [0; 1024];
+    let mut data = Vec::new();
+    let header_end = loop {
+        let len = stream.read(&mut buffer).ok()?;
+        data.extend_from_slice(&buffer[..len]);
+        if let Some(idx) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break idx + 4;
+        }
+        if len == 0 {
+            break data.len();
+        }
+    };
+    if data.is_empty() {
+        return None;
+    }
+    let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+    let content_length = content_length(&parse_headers(&head));
+    // parse_request turns a body that's too big away, so there's no reading it
+    while content_length <= MAX_BODY_BYTES && data.len() < header_end + content_length {
+        let len = stream.read(&mut buffer).ok()?;
+        if len == 0 {
+            break;
+        }
+        data.extend_from_slice(&buffer[..len]);
+    }
+    let body = data.split_off(header_end);
+    Some((head, body))
+}
+
+fn content_length(headers: &HashMap<String, String>) -> usize {
+    headers
+        .get("content-length")
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(0)
+}
+
+fn parse_request(head: &str, body: Vec<u8>) -> Result<Request, Error> {
+    let headers = parse_headers(head);
+    let () = if content_length(&headers) > MAX_BODY_BYTES {
+        Err(Error::new(
+            ErrorCode::PayloadTooLarge,
+            format!(
+                "Payload too large: bodies are limited to {} bytes",
+                MAX_BODY_BYTES
+            ),
+        ))
+    } else {
+        Ok(())
+    }?;
+    let req_fst_line = head.lines().next().unwrap_or("");
+    let mut req_fst_line_it = req_fst_line.split(' ');
+    let (method, full_path) = match (req_fst_line_it.next(), req_fst_line_it.next()) {
+        (Some(method), Some(full_path)) if !method.is_empty() && full_path.starts_with('/') => {
+            Ok((method.to_string(), full_path))
+        }
+        _ => Err(Error::new(
+            ErrorCode::BadRequest,
+            format!("Malformed request line: {:?}", req_fst_line),
+        )),
+    }?;
+    let mut full_path_it = full_path.split("?");
+    let path = full_path_it.next().unwrap().to_string();
+    let query_str_it = {
+        match full_path_it.next() {
+            Some(query_str) => query_str.split("&"),
+            None => {
+                // Make the split empty
+                let mut split = "".split("&");
+                split.next().unwrap();
+                split
+            }
+        }
+    };
+    let mut query_args = HashMap::new();
+    for query_arg_str in query_str_it {
+        let mut query_arg_str_it = query_arg_str.split("=");
+        query_args.insert(
+            query_arg_str_it.next().unwrap().to_string(),
+            query_arg_str_it.collect::<Vec<&str>>().join("="),
+        );
+    }
+    Ok(Request {
+        method,
+        path,
+        query_args,
+        headers,
+        body,
+    })
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum BoardFormat {
+    Json,
+    Csv,
+    Binary,
+}
+
+// Takes the first listed media range we can serve, ignoring q-values other than
+// q=0, which rules a range out. No header at all means JSON.
+fn board_format(accept: Option<&str>) -> Option<BoardFormat> {
+    let accept = match accept {
+        None => return Some(BoardFormat::Json),
+        Some(accept) => accept,
+    };
+    accept.split(',').find_map(|range| {
+        let mut parts = range.split(';').map(|part| part.trim());
+        let media = parts.next().unwrap_or("").to_ascii_lowercase();
+        let quality = |param: &str| {
+            let (name, value) = param.split_once('=')?;
+            if name.trim() == "q" {
+                value.trim().parse::<f32>().ok()
+            } else {
+                None
+            }
+        };
+        if parts.any(|param| quality(param) == Some(0.0)) {
+            return None;
+        }
+        match media.as_str() {
+            "application/json" | "application/*" | "*/*" => Some(BoardFormat::Json),
+            "text/plain" | "text/*" => Some(BoardFormat::Csv),
+            "application/octet-stream" => Some(BoardFormat::Binary),
+            _ => None,
+        }
+    })
+}
+
+fn allowed_methods(path: &str) -> Option<&'static [&'static str]> {
+    match path {
+        "/healthz" | "/version" | "/games" => Some(&["GET"]),
+        "/game" | "/diff" | "/fen" | "/pgn" | "/legal" | "/allmoves" | "/history" | "/piece"
+        | "/square" | "/preview" | "/threats" | "/pins" | "/stats" | "/eval" | "/analyze"
+        | "/probe" | "/mate" | "/why" => Some(&["GET"]),
+        "/load_pgn" | "/load_fen" | "/load_bytes" | "/play" | "/shutdown" => Some(&["POST"]),
+        "/new" | "/setup" | "/place" | "/clear" | "/turn" | "/perft" | "/seed" => {
+            Some(&["GET", "POST"])
+        }
+        "/move" | "/promote" | "/undo" | "/goto" | "/ai" | "/random" | "/resign"
+        | "/offer_draw" | "/accept_draw" | "/claim_draw" | "/takeback" | "/time_control" => {
+            Some(&["GET", "POST"])
+        }
+        _ => None,
+    }
+}
+
+// A square index from 0 (a1) to 63 (h8)
+fn location_from_string(s: &str) -> Result<Location, Error> {
+    match s.parse::<u8>() {
+        Ok(i) if i < 64 => Ok(Location { x: i % 8, y: i / 8 }),
+        _ => Err(invalid_arg(format!("Invalid square index {}", s))),
+    }
+}
+
+fn get_index(query_args: &HashMap<String, String>, key: &str) -> Result<Location, Error> {
+    match query_args.get(key) {
+        None => Err(missing_arg(key)),
+        Some(raw) => location_from_string(raw),
+    }
+}
+
+fn get_from_to(query_args: &HashMap<String, String>) -> Result<(Location, Location), Error> {
+    Ok((get_index(query_args, "from")?, get_index(query_args, "to")?))
+}
+
+fn missing_arg(key: &str) -> Error {
+    Error::new(ErrorCode::MissingArgument, format!("Missing {}", key))
+}
+
+fn invalid_arg(message: String) -> Error {
+    Error::new(ErrorCode::InvalidArgument, message)
+}
+
+fn no_moves() -> Error {
+    Error::new(ErrorCode::NoMoves, "No moves available".to_string())
+}
+
+fn get_square(query_args: &HashMap<String, String>, key: &str) -> Result<Location, Error> {
+    match query_args.get(key) {
+        None => Err(missing_arg(key)),
+        Some(raw) => raw.parse::<Location>(),
+    }
+}
+
+// A comma-separated UCI line such as e2e4,e7e5; empty means no moves
+fn get_moves(query_args: &HashMap<String, String>) -> Result<Vec<Move>, Error> {
+    match query_args.get("moves") {
+        None => Err(missing_arg("moves")),
+        Some(raw) if raw.is_empty() => Ok(Vec::new()),
+        Some(raw) => raw.split(',').map(|token| token.parse::<Move>()).collect(),
+    }
+}
+
+fn get_search_limits(
+    query_args: &HashMap<String, String>,
+) -> Result<(u8, Option<Duration>), Error> {
+    let budget = match query_args.get("ms") {
+        None => None,
+        Some(ms) => Some(Duration::from_millis(
+            ms.parse::<u64>()
+                .map_err(|_| invalid_arg("Invalid ms".to_string()))?,
+        )),
+    };
+    let default_depth = if budget.is_some() {
+        search::MAX_DEPTH
+    } else {
+        3
+    };
+    let depth = match query_args.get("depth") {
+        None => default_depth,
+        Some(depth) => depth
+            .parse::<u8>()
+            .map_err(|_| invalid_arg("Invalid depth".to_string()))?,
+    };
+    Ok((depth, budget))
+}
+
+fn get_level(query_args: &HashMap<String, String>) -> Result<Option<search::SearchParams>, Error> {
+    match query_args.get("level") {
+        None => Ok(None),
+        Some(raw) => match raw.parse::<u8>() {
+            Ok(level) if (1..=10).contains(&level) => Ok(Some(search::params_for_level(level))),
+            _ => Err(invalid_arg(format!(
+                "Invalid level {}: expected 1 to 10",
+                raw
+            ))),
+        },
+    }
+}
+
+fn time_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+}
+
+// An explicit seed wins over the game's own
+fn get_seed(query_args: &HashMap<String, String>, board: &mut Board) -> Result<u64, Error> {
+    match query_args.get("seed") {
+        None => Ok(board.next_seed()),
+        Some(raw) => raw
+            .parse::<u64>()
+            .map_err(|_| invalid_arg(format!("Invalid seed {}", raw))),
+    }
+}
+
+fn get_ply(query_args: &HashMap<String, String>) -> Result<usize, Error> {
+    match query_args.get("ply") {
+        None => Err(missing_arg("ply")),
+        Some(raw) => raw
+            .parse::<usize>()
+            .map_err(|_| invalid_arg(format!("Invalid ply {}", raw))),
+    }
+}
+
+fn get_perft_depth(query_args: &HashMap<String, String>) -> Result<u8, Error> {
+    let depth = match query_args.get("depth") {
+        None => Err(missing_arg("depth")),
+        Some(raw) => raw
+            .parse::<u8>()
+            .map_err(|_| invalid_arg(format!("Invalid depth {}", raw))),
+    }?;
+    if (1..=MAX_PERFT_DEPTH).contains(&depth) {
+        Ok(depth)
+    } else {
+        Err(invalid_arg(format!(
+            "Invalid depth {}: perft depth must be between 1 and {}",
+            depth, MAX_PERFT_DEPTH
+        )))
+    }
+}
+
+fn get_lines(query_args: &HashMap<String, String>) -> Result<usize, Error> {
+    let lines = match query_args.get("lines") {
+        None => Err(missing_arg("lines")),
+        Some(raw) => raw
+            .parse::<usize>()
+            .map_err(|_| invalid_arg(format!("Invalid lines {}", raw))),
+    }?;
+    if (1..=MAX_LINES).contains(&lines) {
+        Ok(lines)
+    } else {
+        Err(invalid_arg(format!(
+            "Invalid lines {}: analysis is limited to 1 to {} lines",
+            lines, MAX_LINES
+        )))
+    }
+}
+
+fn get_mate_moves(query_args: &HashMap<String, String>) -> Result<u8, Error> {
+    let moves = match query_args.get("n") {
+        None => Err(missing_arg("n")),
+        Some(raw) => raw
+            .parse::<u8>()
+            .map_err(|_| invalid_arg(format!("Invalid n {}", raw))),
+    }?;
+    if (1..=MAX_MATE_MOVES).contains(&moves) {
+        Ok(moves)
+    } else {
+        Err(invalid_arg(format!(
+            "Invalid n {}: mate search is limited to 1 to {} moves",
+            moves, MAX_MATE_MOVES
+        )))
+    }
+}
+
+fn get_eval_config(query_args: &HashMap<String, String>) -> Result<EvalConfig, Error> {
+    let mut config = EvalConfig::default();
+    for (key, value) in query_args {
+        let weight = match key.as_str() {
+            "pawn" => &mut config.pawn,
+            "knight" => &mut config.knight,
+            "bishop" => &mut config.bishop,
+            "rook" => &mut config.rook,
+            "queen" => &mut config.queen,
+            "mobility" => &mut config.mobility,
+            _ => continue,
+        };
+        *weight = value
+            .parse::<i32>()
+            .map_err(|_| invalid_arg(format!("Invalid weight for {}", key)))?;
+    }
+    Ok(config)
+}
+
+fn color_from_string(s: &str) -> Result<piece::Color, Error> {
+    use piece::Color;
+    match s {
+        "white" => Ok(Color::White),
+        "black" => Ok(Color::Black),
+        _ => Err(invalid_arg(format!("Invalid color {}", s))),
+    }
+}
+
+fn get_color(query_args: &HashMap<String, String>) -> Result<piece::Color, Error> {
+    match query_args.get("color") {
+        None => Err(missing_arg("color")),
+        Some(raw) => color_from_string(raw),
+    }
+}
+
+fn get_time_control(query_args: &HashMap<String, String>) -> Result<(Duration, Duration), Error> {
+    let ms = |key: &str| match query_args.get(key) {
+        None => Err(missing_arg(key)),
+        Some(raw) => raw
+            .parse::<u64>()
+            .map(Duration::from_millis)
+            .map_err(|_| invalid_arg(format!("Invalid {} {}", key, raw))),
+    };
+    let base = ms("base_ms")?;
+    let increment = match query_args.get("increment_ms") {
+        None => Duration::ZERO,
+        Some(_) => ms("increment_ms")?,
+    };
+    if base.is_zero() {
+        return Err(invalid_arg(
+            "Invalid base_ms 0: the clock needs some time to start with".to_string(),
+        ));
+    }
+    Ok((base, increment))
+}
+
+fn get_orientation(query_args: &HashMap<String, String>) -> Result<piece::Color, Error> {
+    match query_args.get("orientation") {
+        None => Ok(piece::Color::White),
+        Some(raw) => color_from_string(raw),
+    }
+}
+
+fn get_piece(query_args: &HashMap<String, String>) -> Result<piece::Piece, Error> {
+    match query_args.get("piece") {
+        None => Err(missing_arg("piece")),
+        Some(raw) => piece::Piece::from_name(raw)
+            .ok_or_else(|| invalid_arg(format!("Invalid piece {}", raw))),
+    }
+}
+
+fn promotion_from_str(raw: &str) -> Result<piece::Type, Error> {
+    let mut chars = raw.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => promotion_from_letter(c),
+        _ => Err(Error::new(
+            ErrorCode::InvalidPromotion,
+            format!("Invalid promotion piece {}", raw),
+        )),
+    }
+}
+
+fn get_promotion_piece(query_args: &HashMap<String, String>) -> Result<piece::Type, Error> {
+    match query_args.get("piece") {
+        None => Err(missing_arg("piece")),
+        Some(raw) => promotion_from_str(raw),
+    }
+}
+
+fn get_promote(query_args: &HashMap<String, String>) -> Result<Option<piece::Type>, Error> {
+    match query_args.get("promote") {
+        None => Ok(None),
+        Some(raw) => promotion_from_str(raw).map(Some),
+    }
+}
+
+#[derive(Serialize)]
+struct PromotionRequiredResponseData {
+    promotion_required: bool,
+    at: Location,
+}
+
+#[derive(Serialize)]
+struct ResponseData {
+    // The legacy CSV form of the cells, in the requested orientation
+    squares: String,
+    #[serde(flatten)]
+    view: BoardView,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FenResponseData {
+    fen: String,
+}
+
+#[derive(Serialize)]
+struct PgnResponseData {
+    pgn: String,
+}
+
+#[derive(Serialize)]
+struct LegalResponseData {
+    legal: bool,
+}
+
+#[derive(Serialize)]
+struct PreviewResponseData {
+    legal: bool,
+    captures: Option<String>,
+    // Only differs from `to` for en passant
+    captured_square: Option<Location>,
+    gives_check: bool,
+    results_in: Status,
+}
+
+#[derive(Serialize)]
+struct IllegalPreviewResponseData {
+    legal: bool,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct WhyResponseData {
+    legal: bool,
+    verdict: MoveVerdict,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct SquareDiffData {
+    square: Location,
+    // Short name such as "wP", null once emptied
+    piece: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SeedResponseData {
+    seed: u64,
+}
+
+#[derive(Serialize)]
+struct MovesResponseData {
+    moves: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct PieceResponseData {
+    square: Location,
+    piece: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SquareResponseData {
+    square: Location,
+    piece: Option<String>,
+    attacked_by_white: bool,
+    attacked_by_black: bool,
+    legal_moves: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct PlayRequestData {
+    moves: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ThreatData {
+    square: Location,
+    piece: String,
+    attackers: u32,
+    defenders: u32,
+}
+
+#[derive(Serialize)]
+struct ThreatsResponseData {
+    color: piece::Color,
+    hanging: Vec<ThreatData>,
+}
+
+#[derive(Serialize)]
+struct PinData {
+    square: Location,
+    piece: String,
+    pinner: Location,
+    pinner_piece: String,
+    direction: (i8, i8),
+    ray: Vec<Location>,
+}
+
+#[derive(Serialize)]
+struct PinsResponseData {
+    color: piece::Color,
+    pins: Vec<PinData>,
+}
+
+#[derive(Serialize)]
+struct PieceCountData {
+    pawn: u8,
+    knight: u8,
+    bishop: u8,
+    rook: u8,
+    queen: u8,
+    king: u8,
+}
+
+#[derive(Serialize)]
+struct StatsResponseData {
+    white: PieceCountData,
+    black: PieceCountData,
+    // Centipawns at the default piece values, positive when White is ahead
+    material_balance: i32,
+    legal_moves: usize,
+    in_check: bool,
+    halfmove_clock: u32,
+    fullmove_number: u32,
+    status: Status,
+}
+
+#[derive(Serialize)]
+struct ShutdownResponseData {
+    shutdown: bool,
+}
+
+#[derive(Serialize)]
+struct HealthResponseData {
+    status: &'static str,
+}
+
+#[derive(Serialize)]
+struct VersionResponseData {
+    version: &'static str,
+    // Baked in when built with GIT_HASH set, e.g. GIT_HASH=$(git rev-parse --short HEAD)
+    git_hash: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct AnalyzeResponseData {
+    score: i32,
+    pv: Vec<String>,
+    best: String,
+    depth: u8,
+    nodes: u64,
+    limit_reached: Option<search::Limit>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    lines: Vec<LineData>,
+}
+
+#[derive(Serialize)]
+struct GameSummaryData {
+    game_id: u64,
+    turn: piece::Color,
+    status: Status,
+    fullmove_number: u32,
+    last_move: Option<Move>,
+}
+
+#[derive(Serialize)]
+struct NewGameResponseData {
+    game_id: u64,
+    white_token: String,
+    black_token: String,
+}
+
+#[derive(Serialize)]
+struct LineData {
+    #[serde(rename = "move")]
+    mv: String,
+    score: i32,
+    pv: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct MateResponseData {
+    mate: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    line: Vec<String>,
+    // Every first move that mates in time, more than one means the puzzle is cooked
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    keys: Vec<String>,
+}
+
+// Static evaluation only, from White's side: positive favours White, negative
+// Black, whoever is to move
+#[derive(Serialize)]
+struct EvalResponseData {
+    score: i32,
+    #[serde(flatten)]
+    terms: EvalTerms,
+}
+
+#[derive(Serialize)]
+struct ProbeResponseData {
+    fen: String,
+    status: Status,
+    // From White's side, like Board::evaluate
+    eval: i32,
+    // The line the side that passed has to meet, scored from the mover's side
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threat: Option<LineData>,
+}
+
+#[derive(Serialize)]
+struct PerftResponseData {
+    total: u64,
+    divide: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize)]
+struct AiResponseData {
+    squares: String,
+    status: Status,
+    from: Location,
+    to: Location,
+    depth: u8,
+    nodes: u64,
+    limit_reached: Option<search::Limit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+}
+
+// A response being built, along with the CORS header lines every response carries
+struct Response {
+    cors: String,
+    bytes: Vec<u8>,
+}
+
+// Without a configured list any origin may read responses. With one, a matching
+// Origin is echoed back and allowed credentials; others get no CORS grant at all.
+fn cors_headers(allowed_origins: Option<&[String]>, origin: Option<&str>) -> String {
+    match (allowed_origins, origin) {
+        (None, _) => "Access-Control-Allow-Origin: *\r\n".to_string(),
+        (Some(allowed), Some(origin)) if allowed.iter().any(|allowed| allowed == origin) => {
+            format!(
+                "Access-Control-Allow-Origin: {}\r\nAccess-Control-Allow-Credentials: true\r\nVary: Origin\r\n",
+                origin
+            )
+        }
+        (Some(_), _) => "Vary: Origin\r\n".to_string(),
+    }
+}
+
+fn success_res(cors: &str, content: String) -> String {
+    format!(
+        "\
+HTTP/1.1 200 OK\r\n\
+{}\
+Content-Type: application/json\r\n\
+Content-Length: {}\r\n\
+\r\n\
+{}",
+        cors,
+        content.len(),
+        content,
+    )
+}
+
+fn text_res(cors: &str, content: String) -> String {
+    format!(
+        "\
+HTTP/1.1 200 OK\r\n\
+{}\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Length: {}\r\n\
+\r\n\
+{}",
+        cors,
+        content.len(),
+        content,
+    )
+}
+
+fn binary_res(cors: &str, content: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "\
+HTTP/1.1 200 OK\r\n\
+{}\
+Content-Type: application/octet-stream\r\n\
+Content-Length: {}\r\n\
+\r\n",
+        cors,
+        content.len(),
+    )
+    .into_bytes();
+    response.extend_from_slice(content);
+    response
+}
+
+fn error_body(e: &Error) -> String {
+    json!({ "error": e.code, "message": e.message }).to_string()
+}
+
+fn error_res(cors: &str, e: &Error, status: &str) -> String {
+    let body = error_body(e);
+    format!(
+        "\
+HTTP/1.1 {}\r\n\
+{}\
+Content-Type: application/json\r\n\
+Content-Length: {}\r\n\
+\r\n\
+{}",
+        status,
+        cors,
+        body.len(),
+        body,
+    )
+}
+
+// The stable code clients key off, carried next to the message that explains it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    BadRequest,
+    GameOver,
+    NoPiece,
+    NotYourTurn,
+    InvalidMove,
+    InvalidPromotion,
+    NoPendingPromotion,
+    PromotionPending,
+    KingInCheck,
+    InvalidUci,
+    NothingToUndo,
+    NoDrawOffer,
+    OwnDrawOffer,
+    NoTakebackRequest,
+    OwnTakebackRequest,
+    InvalidTakeback,
+    NoDrawToClaim,
+    NoMoves,
+    MissingToken,
+    MissingArgument,
+    InvalidArgument,
+    InvalidNullMove,
+    Forbidden,
+    InvalidPosition,
+    InvalidFen,
+    InvalidBody,
+    SetupMode,
+    NotSetupMode,
+    NotFound,
+    MethodNotAllowed,
+    NotAcceptable,
+    PayloadTooLarge,
+    ShuttingDown,
+    RateLimited,
+    Internal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Error {
+    code: ErrorCode,
+    message: String,
+}
+
+impl Error {
+    fn new(code: ErrorCode, message: String) -> Error {
+        Error { code, message }
+    }
+
+    // Keeps the code while saying where the error came from
+    fn context(self, describe: impl FnOnce(&str) -> String) -> Error {
+        Error {
+            code: self.code,
+            message: describe(&self.message),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+fn write_data<T: Serialize>(data: T, out: &mut Response) {
+    let body = json!(data).to_string();
+    let response = success_res(&out.cors, body);
+    out.bytes.write_all(response.as_bytes()).unwrap();
+}
+
+fn method_not_allowed_res(cors: &str, allowed: &[&str]) -> String {
+    let body = error_body(&Error::new(
+        ErrorCode::MethodNotAllowed,
+        "Method not allowed".to_string(),
+    ));
+    format!(
+        "\
+HTTP/1.1 405 Method Not Allowed\r\n\
+{}\
+Allow: {}\r\n\
+Content-Type: application/json\r\n\
+Content-Length: {}\r\n\
+\r\n\
+{}",
+        cors,
+        allowed.join(", "),
+        body.len(),
+        body,
+    )
+}
+
+fn write_board(board: &Board, out: &mut Response) {
+    write_board_oriented(board, piece::Color::White, out);
+}
+
+// Set when a move made from an earlier ply dropped the line that followed it
+fn discard_warning(board: &Board, upcoming: usize) -> Option<String> {
+    if upcoming > 0 && board.upcoming_plies() == 0 {
+        Some(format!("Discarded {} later plies", upcoming))
+    } else {
+        None
+    }
+}
+
+fn write_moved_board(board: &Board, upcoming: usize, out: &mut Response) {
+    let data = ResponseData {
+        squares: board_as_str(board, piece::Color::White),
+        view: board.snapshot(),
+        warning: discard_warning(board, upcoming),
+    };
+    write_data(data, out);
+}
+
+fn write_board_oriented(board: &Board, perspective: piece::Color, out: &mut Response) {
+    let data = ResponseData {
+        squares: board_as_str(board, perspective),
+        view: board.snapshot_oriented(perspective),
+        warning: None,
+    };
+    write_data(data, out);
+}
+
+fn write_board_csv(board: &Board, perspective: piece::Color, out: &mut Response) {
+    let response = text_res(&out.cors, board_as_str(board, perspective));
+    out.bytes.write_all(response.as_bytes()).unwrap();
+}
+
+fn write_board_bytes(board: &Board, out: &mut Response) {
+    let response = binary_res(&out.cors, &board.to_bytes());
+    out.bytes.write_all(&response).unwrap();
+}
+
+fn piece_counts(board: &Board, color: piece::Color) -> PieceCountData {
+    use piece::Type;
+    PieceCountData {
+        pawn: board.piece_count(color, Type::Pawn),
+        knight: board.piece_count(color, Type::Knight),
+        bishop: board.piece_count(color, Type::Bishop),
+        rook: board.piece_count(color, Type::Rook),
+        queen: board.piece_count(color, Type::Queen),
+        king: board.piece_count(color, Type::King),
+    }
+}
+
+fn write_analysis(board: &Board, result: search::SearchResult, out: &mut Response) {
+    let pv = board.san_line(&result.pv);
+    let data = AnalyzeResponseData {
+        score: result.score,
+        best: pv[0].clone(),
+        pv,
+        depth: result.depth,
+        nodes: result.nodes,
+        limit_reached: result.limit,
+        lines: Vec::new(),
+    };
+    write_data(data, out);
+}
+
+// The first line is also reported at the top level, as for a single line
+fn write_lines(board: &Board, results: Vec<search::SearchResult>, out: &mut Response) {
+    let lines: Vec<LineData> = results
+        .iter()
+        .map(|result| {
+            let pv = board.san_line(&result.pv);
+            LineData {
+                mv: pv[0].clone(),
+                score: result.score,
+                pv,
+            }
+        })
+        .collect();
+    let best = &results[0];
+    let data = AnalyzeResponseData {
+        score: best.score,
+        pv: lines[0].pv.clone(),
+        best: lines[0].mv.clone(),
+        depth: best.depth,
+        nodes: results.iter().map(|result| result.nodes).max().unwrap(),
+        limit_reached: results.iter().find_map(|result| result.limit),
+        lines,
+    };
+    write_data(data, out);
+}
+
+fn write_mate(board: &Board, mates: Vec<(Move, Vec<Move>)>, out: &mut Response) {
+    let legal_moves = board.all_legal_moves();
+    let shortest = mates
+        .iter()
+        .map(|(_, line)| line)
+        .min_by_key(|line| line.len());
+    let data = MateResponseData {
+        mate: shortest.is_some(),
+        line: shortest.map_or(Vec::new(), |line| board.san_line(line)),
+        keys: mates
+            .iter()
+            .map(|&(mv, _)| board.san(mv, &legal_moves))
+            .collect(),
+    };
+    write_data(data, out);
+}
+
+fn write_perft(divide: Vec<(Move, u64)>, out: &mut Response) {
+    let data = PerftResponseData {
+        total: divide.iter().map(|&(_, nodes)| nodes).sum(),
+        divide: divide
+            .into_iter()
+            .map(|(mv, nodes)| (mv.to_string(), nodes))
+            .collect(),
+    };
+    write_data(data, out);
+}
+
+fn write_ai_move(
+    board: &Board,
+    result: search::SearchResult,
+    warning: Option<String>,
+    out: &mut Response,
+) {
+    let data = AiResponseData {
+        squares: board_as_str(board, piece::Color::White),
+        status: board.status(),
+        from: result.best.from,
+        to: result.best.to,
+        depth: result.depth,
+        nodes: result.nodes,
+        limit_reached: result.limit,
+        warning,
+    };
+    write_data(data, out);
+}
+
+fn write_err(e: Error, out: &mut Response) {
+    let response = error_res(&out.cors, &e, "400 Bad Request");
+    out.bytes.write_all(response.as_bytes()).unwrap();
+}
+
+fn write_forbidden(e: Error, out: &mut Response) {
+    let response = error_res(&out.cors, &e, "403 Forbidden");
+    out.bytes.write_all(response.as_bytes()).unwrap();
+}
+
+fn write_not_found(e: Error, out: &mut Response) {
+    let response = error_res(&out.cors, &e, "404 Not Found");
+    out.bytes.write_all(response.as_bytes()).unwrap();
+}
+
+fn write_internal_error(e: Error, out: &mut Response) {
+    let response = error_res(&out.cors, &e, "500 Internal Server Error");
+    out.bytes.write_all(response.as_bytes()).unwrap();
+}
+
+fn write_not_acceptable(e: Error, out: &mut Response) {
+    let response = error_res(&out.cors, &e, "406 Not Acceptable");
+    out.bytes.write_all(response.as_bytes()).unwrap();
+}
+
+fn too_many_requests_res(cors: &str, retry_after: u64, e: &Error) -> String {
+    let body = error_body(e);
+    format!(
+        "\
+HTTP/1.1 429 Too Many Requests\r\n\
+{}\
+Retry-After: {}\r\n\
+Content-Type: application/json\r\n\
+Content-Length: {}\r\n\
+\r\n\
+{}",
+        cors,
+        retry_after,
+        body.len(),
+        body,
+    )
+}
+
+fn write_too_many_requests(retry_after: Duration, out: &mut Response) {
+    // Retry-After is whole seconds, and rounding down would invite a retry that's still early
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    let e = Error::new(
+        ErrorCode::RateLimited,
+        format!("Too many requests: retry in {}s", secs),
+    );
+    let response = too_many_requests_res(&out.cors, secs, &e);
+    out.bytes.write_all(response.as_bytes()).unwrap();
+}
+
+// Answers a CORS preflight, listing what the browser may send to this path
+fn preflight_res(cors: &str, allowed: &[&str]) -> String {
+    format!(
+        "\
+HTTP/1.1 204 No Content\r\n\
+{}\
+Access-Control-Allow-Methods: {}, OPTIONS\r\n\
+Access-Control-Allow-Headers: Content-Type\r\n\
+Access-Control-Max-Age: 86400\r\n\
+Content-Length: 0\r\n\
+\r\n",
+        cors,
+        allowed.join(", "),
+    )
+}
+
+fn write_preflight(allowed: &[&str], out: &mut Response) {
+    let response = preflight_res(&out.cors, allowed);
+    out.bytes.write_all(response.as_bytes()).unwrap();
+}
+
+fn write_method_not_allowed(allowed: &[&str], out: &mut Response) {
+    let response = method_not_allowed_res(&out.cors, allowed);
+    out.bytes.write_all(response.as_bytes()).unwrap();
+}
+
+const DEFAULT_BIND: &str = "127.0.0.1:8080";
+const DEFAULT_SAVE_FILE: &str = "game.pgn";
+const DEFAULT_GAME_ID: u64 = 1;
+const DEFAULT_RATE_LIMIT: usize = 20;
+const DEFAULT_RATE_WINDOW_SECS: u64 = 60;
+const DEFAULT_AUTOSAVE_SECS: u64 = 5;
+
+// Far more than the longest PGN or move list a client would send
+const MAX_BODY_BYTES: usize = 1 << 20;
+
+// A client that goes this long without sending anything mid-request is dropped
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long the acceptor waits after a failed accept, which tends to fail again
+// straight away when it's out of file descriptors
+const ACCEPT_RETRY: Duration = Duration::from_millis(10);
+
+#[derive(Clone)]
+struct ServerConfig {
+    shutdown_token: Option<String>,
+    // None allows every origin
+    allowed_origins: Option<Vec<String>>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum LogLevel {
+    Error,
+    Info,
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    // Accepts the RUST_LOG names too, folding warn into error and trace into debug
+    fn from_str(s: &str) -> Result<LogLevel, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" | "warn" => Ok(LogLevel::Error),
+            "info" => Ok(LogLevel::Info),
+            "debug" | "trace" => Ok(LogLevel::Debug),
+            _ => Err(format!("Invalid log level {}", s)),
+        }
+    }
+}
+
+// Set once at startup; a global so any handler can log without threading it through
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+fn log(level: LogLevel, message: fmt::Arguments) {
+    if log_enabled(level) {
+        println!("{}", message);
+    }
+}
+
+// --log=LEVEL, then RUST_LOG, then info
+fn get_log_level() -> Result<LogLevel, String> {
+    let arg = std::env::args().find_map(|arg| arg.strip_prefix("--log=").map(str::to_string));
+    match arg.or_else(|| std::env::var("RUST_LOG").ok()) {
+        None => Ok(LogLevel::Info),
+        Some(raw) => raw.parse::<LogLevel>(),
+    }
+}
+
+// Sliding window per client, so a burst can't land at the edge of two fixed windows
+struct RateLimiter {
+    // 0 turns limiting off
+    limit: usize,
+    window: Duration,
+    hits: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    // Err holds how long until the client's oldest request leaves the window
+    fn check(&mut self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        if self.limit == 0 {
+            return Ok(());
+        }
+        let window = self.window;
+        // Forget clients that have gone quiet, so the map only holds recent ones
+        self.hits.retain(|_, hits| {
+            while hits
+                .front()
+                .is_some_and(|&hit| now.duration_since(hit) >= window)
+            {
+                hits.pop_front();
+            }
+            !hits.is_empty()
+        });
+        let hits = self.hits.entry(ip).or_default();
+        if hits.len() >= self.limit {
+            return Err(window - now.duration_since(hits[0]));
+        }
+        hits.push_back(now);
+        Ok(())
+    }
+}
+
+// Requests without a game_id go to the default game, which is the one saved to disk
+struct Games {
+    games: BTreeMap<u64, Game>,
+    next_id: u64,
+}
+
+struct Game {
+    board: Board,
+    // None leaves the game open to anyone, as the default game is
+    tokens: Option<PlayerTokens>,
+}
+
+struct PlayerTokens {
+    white: String,
+    black: String,
+}
+
+impl PlayerTokens {
+    fn color(&self, token: &str) -> Option<piece::Color> {
+        // Both are compared every time so the answer takes as long either way
+        let (white, black) = (
+            same_token(token, &self.white),
+            same_token(token, &self.black),
+        );
+        if white {
+            Some(piece::Color::White)
+        } else if black {
+            Some(piece::Color::Black)
+        } else {
+            None
+        }
+    }
+}
+
+// Looks at every byte rather than stopping at the first difference, so the time
+// taken doesn't tell a guesser how much of the token they have right
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+const TOKEN_BYTES: usize = 16;
+
+// Straight from the OS, since a seeded generator's output gives away its next tokens
+fn random_token() -> Result<String, Error> {
+    let mut bytes = [0; TOKEN_BYTES];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .map_err(|e| {
+            Error::new(
+                ErrorCode::Internal,
+                format!("Could not generate a token: {}", e),
+            )
+        })?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+impl Games {
+    fn new(default: Board) -> Games {
+        let mut games = BTreeMap::new();
+        games.insert(
+            DEFAULT_GAME_ID,
+            Game {
+                board: default,
+                tokens: None,
+            },
+        );
+        Games {
+            games,
+            next_id: DEFAULT_GAME_ID + 1,
+        }
+    }
+
+    fn create(&mut self, board: Board) -> Result<(u64, &PlayerTokens), Error> {
+        let tokens = PlayerTokens {
+            white: random_token()?,
+            black: random_token()?,
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        let game = self.games.entry(id).or_insert(Game {
+            board,
+            tokens: Some(tokens),
+        });
+        Ok((id, game.tokens.as_ref().unwrap()))
+    }
+
+    fn get_mut(&mut self, id: u64) -> Result<&mut Game, Error> {
+        self.games
+            .get_mut(&id)
+            .ok_or_else(|| Error::new(ErrorCode::NotFound, format!("Unknown game {}", id)))
+    }
+}
+
+// Only changes to the game need a player's token, anyone can read it. Moves
+// need the side to move's token and acting for a color needs that color's.
+// Rewriting the position or its history is refused outright, since either
+// player could use it to decide the game alone.
+fn authorize(game: &Game, request: &Request) -> Result<(), Error> {
+    let tokens = match &game.tokens {
+        None => return Ok(()),
+        Some(tokens) => tokens,
+    };
+    let path = request.path.as_str();
+    let changes_game = allowed_methods(path).is_some_and(|allowed| {
+        allowed.contains(&"POST") && allowed.contains(&request.method.as_str())
+    }) && !matches!(path, "/perft" | "/shutdown");
+    if !changes_game {
+        return Ok(());
+    }
+    let () = match path {
+        "/setup" | "/place" | "/clear" | "/turn" | "/load_fen" | "/load_pgn" | "/load_bytes"
+        | "/goto" | "/play" | "/time_control" => Err(Error::new(
+            ErrorCode::Forbidden,
+            format!(
+                "Forbidden: {} is not allowed in a game with player tokens",
+                path
+            ),
+        )),
+        "/undo" => Err(Error::new(
+            ErrorCode::Forbidden,
+            "Forbidden: moves are taken back through /takeback in a game with player tokens"
+                .to_string(),
+        )),
+        _ => Ok(()),
+    }?;
+    let color = match request.query_args.get("token") {
+        None => Err(Error::new(
+            ErrorCode::MissingToken,
+            format!("Missing token: {} needs a player's token", path),
+        )),
+        Some(token) => tokens
+            .color(token)
+            .ok_or_else(|| Error::new(ErrorCode::Forbidden, "Invalid token".to_string())),
+    }?;
+    match path {
+        "/move" | "/promote" | "/ai" | "/random" if game.board.turn != color => Err(Error::new(
+            ErrorCode::NotYourTurn,
+            format!("Not your turn: it is {}'s move", game.board.turn.name()),
+        )),
+        "/resign" | "/offer_draw" | "/accept_draw" | "/takeback" => {
+            match get_color(&request.query_args) {
+                Ok(acting) if acting != color => Err(Error::new(
+                    ErrorCode::Forbidden,
+                    format!(
+                        "Invalid token for {}: it plays {}",
+                        acting.name(),
+                        color.name()
+                    ),
+                )),
+                _ => Ok(()),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+// Searches that take real CPU time; everything else is cheap enough to leave open
+fn is_expensive(request: &Request) -> bool {
+    let path = request.path.as_str();
+    let searches = matches!(path, "/ai" | "/analyze" | "/mate" | "/perft")
+        || (path == "/probe" && request.query_args.contains_key("null"));
+    searches
+        && allowed_methods(path).is_some_and(|allowed| allowed.contains(&request.method.as_str()))
+}
+
+// CHESS_RATE_LIMIT expensive requests per client every CHESS_RATE_WINDOW seconds
+fn get_rate_limiter() -> Result<RateLimiter, String> {
+    let limit = match std::env::var("CHESS_RATE_LIMIT") {
+        Err(_) => DEFAULT_RATE_LIMIT,
+        Ok(raw) => raw
+            .parse::<usize>()
+            .map_err(|_| format!("Invalid CHESS_RATE_LIMIT {}", raw))?,
+    };
+    let secs = match std::env::var("CHESS_RATE_WINDOW") {
+        Err(_) => DEFAULT_RATE_WINDOW_SECS,
+        Ok(raw) => match raw.parse::<u64>() {
+            Ok(secs) if secs > 0 => secs,
+            _ => return Err(format!("Invalid CHESS_RATE_WINDOW {}", raw)),
+        },
+    };
+    Ok(RateLimiter {
+        limit,
+        window: Duration::from_secs(secs),
+        hits: HashMap::new(),
+    })
+}
+
+// CHESS_CORS_ORIGIN is a comma-separated list; unset, empty or * keeps the wildcard
+fn get_allowed_origins() -> Option<Vec<String>> {
+    let raw = std::env::var("CHESS_CORS_ORIGIN").ok()?;
+    let origins: Vec<String> = raw
+        .split(',')
+        .map(|origin| origin.trim().to_string())
+        .filter(|origin| !origin.is_empty())
+        .collect();
+    if origins.is_empty() || origins.iter().any(|origin| origin == "*") {
+        None
+    } else {
+        Some(origins)
+    }
+}
+
+// Depth 6 from the start position already takes seconds, and the server is single-threaded
+const MAX_PERFT_DEPTH: u8 = 5;
+// Depth 5 from the start position fits, the busier middlegames don't
+const MAX_PERFT_NODES: u64 = 5_000_000;
+
+// A quick look, since /probe is otherwise just a replay
+const THREAT_DEPTH: u8 = 3;
+
+// Each line is a full search of its own
+const MAX_LINES: usize = 5;
+
+// Each extra move multiplies the search by two plies of branching
+const MAX_MATE_MOVES: u8 = 3;
+
+// CHESS_SAVE_FILE set to an empty string disables saving
+fn get_save_path() -> Option<PathBuf> {
+    match std::env::var("CHESS_SAVE_FILE") {
+        Ok(raw) if raw.is_empty() => None,
+        Ok(raw) => Some(PathBuf::from(raw)),
+        Err(_) => Some(PathBuf::from(DEFAULT_SAVE_FILE)),
+    }
+}
+
+// Seconds between saves made while no requests come in, through CHESS_AUTOSAVE_SECS
+fn get_autosave_interval() -> Result<Duration, String> {
+    match std::env::var("CHESS_AUTOSAVE_SECS") {
+        Err(_) => Ok(Duration::from_secs(DEFAULT_AUTOSAVE_SECS)),
+        Ok(raw) => match raw.parse::<u64>() {
+            Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+            _ => Err(format!("Invalid CHESS_AUTOSAVE_SECS {}", raw)),
+        },
+    }
+}
+
+// A save that can't be read back is set aside for a new game rather than stopping the server
+fn resume_game(path: &Path) -> Board {
+    if !path.exists() {
+        return Board::new();
+    }
+    match Board::load_from(path) {
+        Ok(board) => {
+            log(
+                LogLevel::Info,
+                format_args!("Resumed game from {}", path.display()),
+            );
+            board
+        }
+        Err(e) => {
+            log(
+                LogLevel::Error,
+                format_args!("Warning: {}, starting a new game", e),
+            );
+            Board::new()
+        }
+    }
+}
+
+// The default game keeps the save path itself and every other game goes next
+// to it under its id, so game.pgn sits beside game-2.pgn, game-3.pgn and so on
+fn game_save_path(base: &Path, id: u64) -> PathBuf {
+    if id == DEFAULT_GAME_ID {
+        return base.to_path_buf();
+    }
+    let stem = base
+        .file_stem()
+        .map_or("game".into(), |stem| stem.to_string_lossy());
+    let name = match base.extension() {
+        None => format!("{}-{}", stem, id),
+        Some(ext) => format!("{}-{}.{}", stem, id, ext.to_string_lossy()),
+    };
+    base.with_file_name(name)
+}
+
+// The game id a file beside the save path belongs to, if it is one of ours
+fn saved_game_id(base: &Path, file: &Path) -> Option<u64> {
+    let name = file.file_name()?.to_string_lossy();
+    let stem = base.file_stem()?.to_string_lossy();
+    let rest = name.strip_prefix(&*stem)?.strip_prefix('-')?;
+    let digits = match base.extension() {
+        None => rest,
+        Some(ext) => rest
+            .strip_suffix(&*ext.to_string_lossy())?
+            .strip_suffix('.')?,
+    };
+    let id = digits.parse::<u64>().ok()?;
+    (id != DEFAULT_GAME_ID && game_save_path(base, id).file_name() == file.file_name())
+        .then_some(id)
+}
+
+// Player tokens ride along as extra PGN tags, which from_pgn skips, so a resumed
+// game still answers to the same two players
+fn game_to_save(game: &Game) -> String {
+    match &game.tokens {
+        None => game.board.to_pgn(),
+        Some(tokens) => format!(
+            "[WhiteToken \"{}\"]\n[BlackToken \"{}\"]\n{}",
+            tokens.white,
+            tokens.black,
+            game.board.to_pgn()
+        ),
+    }
+}
+
+fn load_game(path: &Path) -> Result<Game, String> {
+    let pgn = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let board =
+        Board::from_pgn(&pgn).map_err(|e| format!("Could not load {}: {}", path.display(), e))?;
+    let tokens = match (pgn_tag(&pgn, "WhiteToken"), pgn_tag(&pgn, "BlackToken")) {
+        (None, None) => Ok(None),
+        (Some(white), Some(black)) => Ok(Some(PlayerTokens { white, black })),
+        _ => Err(format!(
+            "Could not load {}: only one player token",
+            path.display()
+        )),
+    }?;
+    Ok(Game { board, tokens })
+}
+
+// The default game comes back as resume_game has it, then every other game saved
+// beside it. One that can't be read back is left out with a warning, and its id
+// isn't handed out again, so its file stays as it was.
+fn resume_games(base: &Path) -> Games {
+    let mut games = Games::new(resume_game(base));
+    let dir = match base.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut ids: Vec<u64> = match fs::read_dir(dir) {
+        Err(_) => Vec::new(),
+        Ok(entries) => entries
+            .filter_map(|entry| saved_game_id(base, &entry.ok()?.path()))
+            .collect(),
+    };
+    ids.sort();
+    for id in ids {
+        games.next_id = games.next_id.max(id + 1);
+        let path = game_save_path(base, id);
+        match load_game(&path) {
+            Ok(game) => {
+                log(
+                    LogLevel::Info,
+                    format_args!("Resumed game {} from {}", id, path.display()),
+                );
+                games.games.insert(id, game);
+            }
+            Err(e) => log(
+                LogLevel::Error,
+                format_args!("Warning: {}, leaving game {} out", e, id),
+            ),
+        }
+    }
+    games
+}
+
+// Only a save that went through counts, so a failed one is tried again next time
+fn autosave(games: &Games, base: &Path, saved: &mut HashMap<u64, String>) {
+    for (&id, game) in games.games.iter() {
+        let contents = game_to_save(game);
+        if saved.get(&id) == Some(&contents) {
+            continue;
+        }
+        match write_save(&game_save_path(base, id), &contents) {
+            Ok(()) => {
+                saved.insert(id, contents);
+            }
+            Err(e) => log(LogLevel::Error, format_args!("Warning: {}", e)),
+        }
+    }
+}
+
+// First command-line argument that isn't a flag, then CHESS_BIND, then the default
+fn get_bind_addr() -> Result<SocketAddr, String> {
+    let raw = std::env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with("--"))
+        .or_else(|| std::env::var("CHESS_BIND").ok())
+        .unwrap_or_else(|| DEFAULT_BIND.to_string());
+    raw.parse::<SocketAddr>()
+        .map_err(|e| format!("Invalid bind address {}: {}", raw, e))
+}
+
+fn get_game_id(query_args: &HashMap<String, String>) -> Result<u64, Error> {
+    match query_args.get("game_id") {
+        None => Ok(DEFAULT_GAME_ID),
+        Some(raw) => raw
+            .parse::<u64>()
+            .map_err(|_| invalid_arg(format!("Invalid game_id {}", raw))),
+    }
+}
+
+// Query arguments arrive as sent, so one holding spaces such as a FEN needs
+// decoding: %XX escapes and + for a space
+fn percent_decode(raw: &str) -> Result<String, Error> {
+    let mut bytes = Vec::new();
+    let mut rest = raw.bytes();
+    while let Some(byte) = rest.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = rest.by_ref().take(2).collect();
+                let decoded = std::str::from_utf8(&hex)
+                    .ok()
+                    .filter(|hex| hex.len() == 2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| invalid_arg(format!("Invalid escape in {}", raw)))?;
+                bytes.push(decoded);
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid_arg(format!("Invalid escape in {}", raw)))
+}
+
+// An on/off argument, off when left out
+fn get_flag(query_args: &HashMap<String, String>, key: &str) -> Result<bool, Error> {
+    match query_args.get(key).map(String::as_str) {
+        None | Some("0") | Some("false") => Ok(false),
+        Some("1") | Some("true") => Ok(true),
+        Some(raw) => Err(invalid_arg(format!("Invalid {} {}", key, raw))),
+    }
+}
+
+// The summaries only read a few fields of each game, so nothing is cloned
+fn game_summaries(games: &Games, active: bool) -> Vec<GameSummaryData> {
+    games
+        .games
+        .iter()
+        .map(|(&game_id, game)| (game_id, &game.board, game.board.status()))
+        .filter(|(_, _, status)| !active || *status == Status::Ongoing)
+        .map(|(game_id, board, status)| GameSummaryData {
+            game_id,
+            turn: board.turn,
+            status,
+            fullmove_number: board.fullmove_number,
+            last_move: board.last_move(),
+        })
+        .collect()
+}
+
+// Routes that read no game at all
+fn is_status_route(path: &str) -> bool {
+    matches!(path, "/healthz" | "/version")
+}
+
+fn handle_status_request(request: &Request, response: &mut Response) {
+    let allowed = allowed_methods(&request.path).unwrap_or(&["GET"]);
+    if request.method == "OPTIONS" {
+        write_preflight(allowed, response);
+    } else if !allowed.contains(&request.method.as_str()) {
+        write_method_not_allowed(allowed, response);
+    } else if request.path == "/healthz" {
+        write_data(HealthResponseData { status: "ok" }, response);
+    } else {
+        let data = VersionResponseData {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: option_env!("GIT_HASH"),
+        };
+        write_data(data, response);
+    }
+}
+
+// Routes that work on the set of games, everything else goes to one game's board
+fn handle_games_request(
+    games: &mut Games,
+    request: &Request,
+    shutdown_token: Option<&str>,
+    response: &mut Response,
+) -> bool {
+    let Request {
+        method,
+        path,
+        query_args,
+        ..
+    } = request;
+    let allowed = allowed_methods(path).is_some_and(|allowed| allowed.contains(&method.as_str()));
+    if allowed && path.eq("/games") {
+        match get_flag(query_args, "active") {
+            Ok(active) => write_data(game_summaries(games, active), response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+        return true;
+    }
+    if allowed && path.eq("/new") {
+        // The position is checked first so a bad FEN never takes up a game id
+        let start = match query_args.get("fen") {
+            None => Ok(Board::new()),
+            Some(raw) => percent_decode(raw).and_then(|fen| Board::from_fen(&fen)),
+        };
+        match start.and_then(|board| games.create(board)) {
+            Ok((game_id, tokens)) => {
+                let data = NewGameResponseData {
+                    game_id,
+                    white_token: tokens.white.clone(),
+                    black_token: tokens.black.clone(),
+                };
+                write_data(data, response);
+            }
+            Err(e) if e.code == ErrorCode::Internal => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_internal_error(e, response)
+            }
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+        return true;
+    }
+    let game = get_game_id(query_args).and_then(|id| games.get_mut(id));
+    match game.and_then(|game| authorize(game, request).map(|()| game)) {
+        Ok(game) => handle_request(&mut game.board, request, shutdown_token, response),
+        Err(e) => {
+            log(LogLevel::Error, format_args!("Error: {}", e));
+            match e.code {
+                ErrorCode::NotFound => write_not_found(e, response),
+                ErrorCode::InvalidArgument => write_err(e, response),
+                _ => write_forbidden(e, response),
+            }
+            true
+        }
+    }
+}
+
+fn handle_request(
+    board: &mut Board,
+    request: &Request,
+    shutdown_token: Option<&str>,
+    response: &mut Response,
+) -> bool {
+    let Request {
+        method,
+        path,
+        query_args,
+        headers,
+        body: bytes,
+    } = request;
+    let body: &str = &String::from_utf8_lossy(bytes);
+    // For warning when a move drops the line ahead of an earlier ply
+    let upcoming = board.upcoming_plies();
+    let allowed = allowed_methods(path);
+    let disallowed = allowed.filter(|allowed| !allowed.contains(&method.as_str()));
+    if let (Some(allowed), "OPTIONS") = (allowed, method.as_str()) {
+        write_preflight(allowed, response);
+    } else if let Some(allowed) = disallowed {
+        write_method_not_allowed(allowed, response);
+    } else if path.eq("/game") {
+        match (
+            get_orientation(query_args),
+            board_format(headers.get("accept").map(String::as_str)),
+        ) {
+            (Err(e), _) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+            (Ok(_), None) => write_not_acceptable(
+                Error::new(
+                    ErrorCode::NotAcceptable,
+                    "Not acceptable: /game serves application/json, text/plain or application/octet-stream".to_string(),
+                ),
+                response,
+            ),
+            (Ok(perspective), Some(BoardFormat::Json)) => {
+                write_board_oriented(board, perspective, response)
+            }
+            (Ok(perspective), Some(BoardFormat::Csv)) => {
+                write_board_csv(board, perspective, response)
+            }
+            // The packed position is always from white's side
+            (Ok(_), Some(BoardFormat::Binary)) => write_board_bytes(board, response),
+        };
+    } else if path.eq("/move") {
+        // Some(square) when the move is waiting on /promote for its piece
+        let result = match (query_args.get("uci"), query_args.get("san")) {
+            (Some(uci), _) => board
+                .parse_uci(uci)
+                .and_then(|mv| board.step(mv.from, mv.to, mv.promote))
+                .map(|()| None),
+            (None, Some(san)) => board
+                .parse_san(san)
+                .and_then(|mv| board.step(mv.from, mv.to, mv.promote))
+                .map(|()| None),
+            (None, None) => get_from_to(query_args).and_then(|(from, to)| {
+                get_promote(query_args).and_then(|promote| match promote {
+                    None if board.promotes(from, to) => {
+                        board.defer_promotion(from, to).map(|()| Some(to))
+                    }
+                    _ => board.step(from, to, promote).map(|()| None),
+                })
+            }),
+        };
+        match result {
+            Ok(None) => write_moved_board(board, upcoming, response),
+            Ok(Some(at)) => write_data(
+                PromotionRequiredResponseData {
+                    promotion_required: true,
+                    at,
+                },
+                response,
+            ),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/promote") {
+        let result = get_promotion_piece(query_args).and_then(|tpe| board.promote(tpe));
+        match result {
+            Ok(()) => write_moved_board(board, upcoming, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/shutdown") {
+        let authorized = shutdown_token
+            .zip(query_args.get("token"))
+            .is_some_and(|(expected, given)| same_token(given, expected));
+        if authorized {
+            write_data(ShutdownResponseData { shutdown: true }, response);
+            return false;
+        }
+        write_forbidden(
+            Error::new(ErrorCode::Forbidden, "Invalid token".to_string()),
+            response,
+        );
+    } else if path.eq("/load_fen") {
+        match Board::from_fen(body) {
+            Ok(loaded) => {
+                *board = loaded;
+                write_board(board, response)
+            }
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/load_bytes") {
+        // The position as /game serves it to Accept: application/octet-stream
+        match Board::from_bytes(bytes) {
+            Ok(loaded) => {
+                *board = loaded;
+                write_board(board, response)
+            }
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/load_pgn") {
+        match Board::from_pgn(body) {
+            Ok(loaded) => {
+                *board = loaded;
+                write_board(board, response)
+            }
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/play") {
+        let result = serde_json::from_str::<PlayRequestData>(body)
+            .map_err(|e| Error::new(ErrorCode::InvalidBody, format!("Invalid body: {}", e)))
+            .and_then(|data| board.play(&data.moves));
+        match result {
+            Ok(()) => write_moved_board(board, upcoming, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/goto") {
+        match get_ply(query_args).and_then(|ply| board.goto(ply)) {
+            Ok(()) => write_board(board, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/undo") {
+        match board.undo() {
+            Ok(()) => write_board(board, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/legal") {
+        let result = get_square(query_args, "from").and_then(|from| {
+            let to = get_square(query_args, "to")?;
+            let promote = get_promote(query_args)?;
+            Ok(board.is_legal(from, to, promote))
+        });
+        match result {
+            Ok(legal) => write_data(LegalResponseData { legal }, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/preview") {
+        let result = get_square(query_args, "from").and_then(|from| {
+            let to = get_square(query_args, "to")?;
+            let promote = get_promote(query_args)?;
+            // An illegal move is an answer here, not an error
+            Ok(board.validate_move(from, to, promote).and_then(|mv| {
+                let captured = board.captured(mv);
+                board.with_moves(&[mv], |after| PreviewResponseData {
+                    legal: true,
+                    captures: captured.map(|(_, piece)| piece.name()),
+                    captured_square: captured.map(|(at, _)| at),
+                    gives_check: after.is_in_check(after.turn),
+                    results_in: after.status(),
+                })
+            }))
+        });
+        match result {
+            Ok(Ok(data)) => write_data(data, response),
+            Ok(Err(e)) => write_data(
+                IllegalPreviewResponseData {
+                    legal: false,
+                    reason: e.message,
+                },
+                response,
+            ),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/why") {
+        let result = get_square(query_args, "from").and_then(|from| {
+            let to = get_square(query_args, "to")?;
+            let promote = get_promote(query_args)?;
+            let (verdict, reason) = board.explain_move(from, to, promote);
+            Ok(WhyResponseData {
+                legal: verdict == MoveVerdict::Legal,
+                verdict,
+                reason,
+            })
+        });
+        match result {
+            Ok(data) => write_data(data, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/allmoves") {
+        let legal_moves = board.all_legal_moves();
+        let moves = legal_moves
+            .iter()
+            .map(|&mv| board.san(mv, &legal_moves))
+            .collect();
+        write_data(MovesResponseData { moves }, response);
+    } else if path.eq("/piece") {
+        match get_square(query_args, "at") {
+            Ok(at) => {
+                let data = PieceResponseData {
+                    square: at,
+                    piece: board.get(at).map(|piece| piece.name()),
+                };
+                write_data(data, response)
+            }
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/pins") {
+        match get_color(query_args) {
+            Ok(color) => {
+                let pins = board
+                    .pins(color)
+                    .into_iter()
+                    .map(|pin| PinData {
+                        square: pin.pinned,
+                        piece: board.get(pin.pinned).map_or(String::new(), |p| p.name()),
+                        pinner: pin.pinner,
+                        pinner_piece: board.get(pin.pinner).map_or(String::new(), |p| p.name()),
+                        direction: pin.direction,
+                        ray: pin.ray,
+                    })
+                    .collect();
+                write_data(PinsResponseData { color, pins }, response)
+            }
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/threats") {
+        match get_color(query_args) {
+            Ok(color) => {
+                let hanging = board
+                    .hanging_pieces(color)
+                    .into_iter()
+                    .filter_map(|sq| {
+                        board.get(sq).map(|piece| ThreatData {
+                            square: sq,
+                            piece: piece.name(),
+                            attackers: board.attackers(sq, color.opposite()).count_ones(),
+                            defenders: board.attackers(sq, color).count_ones(),
+                        })
+                    })
+                    .collect();
+                write_data(ThreatsResponseData { color, hanging }, response)
+            }
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/square") {
+        match get_square(query_args, "at") {
+            Ok(at) => {
+                let legal_moves = board.all_legal_moves();
+                let data = SquareResponseData {
+                    square: at,
+                    piece: board.get(at).map(|piece| piece.name()),
+                    attacked_by_white: board.is_square_attacked(at, piece::Color::White),
+                    attacked_by_black: board.is_square_attacked(at, piece::Color::Black),
+                    legal_moves: legal_moves
+                        .iter()
+                        .filter(|mv| mv.from == at)
+                        .map(|&mv| board.san(mv, &legal_moves))
+                        .collect(),
+                };
+                write_data(data, response)
+            }
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/stats") {
+        let view = board.snapshot();
+        let data = StatsResponseData {
+            white: piece_counts(board, piece::Color::White),
+            black: piece_counts(board, piece::Color::Black),
+            material_balance: board.evaluate_terms(&EvalConfig::default()).material,
+            legal_moves: board.all_legal_moves().len(),
+            in_check: board.is_in_check(view.turn),
+            halfmove_clock: view.halfmove_clock,
+            fullmove_number: view.fullmove_number,
+            status: view.status,
+        };
+        write_data(data, response);
+    } else if path.eq("/diff") {
+        let diff: Vec<SquareDiffData> = board
+            .last_move_diff()
+            .into_iter()
+            .map(|(square, piece)| SquareDiffData {
+                square,
+                piece: piece.map(|piece| piece.name()),
+            })
+            .collect();
+        write_data(diff, response);
+    } else if path.eq("/history") {
+        let moves = board.san_history();
+        write_data(MovesResponseData { moves }, response);
+    } else if path.eq("/fen") {
+        write_data(
+            FenResponseData {
+                fen: board.to_fen(),
+            },
+            response,
+        );
+    } else if path.eq("/pgn") {
+        write_data(
+            PgnResponseData {
+                pgn: board.to_pgn(),
+            },
+            response,
+        );
+    } else if path.eq("/perft") {
+        // A fen argument or a POSTed FEN counts from that position instead of the
+        // current one
+        let result = get_perft_depth(query_args).and_then(|depth| {
+            let position = match query_args.get("fen") {
+                Some(raw) => Board::from_fen(&percent_decode(raw)?)?,
+                None if body.trim().is_empty() => board.without_history(),
+                None => Board::from_fen(body)?,
+            };
+            position.divide(depth, MAX_PERFT_NODES).ok_or_else(|| {
+                invalid_arg(format!(
+                    "Perft to depth {} counts more than {} nodes",
+                    depth, MAX_PERFT_NODES
+                ))
+            })
+        });
+        match result {
+            Ok(divide) => write_perft(divide, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/eval") {
+        match get_eval_config(query_args) {
+            Ok(config) => {
+                let terms = board.evaluate_terms(&config);
+                let data = EvalResponseData {
+                    score: terms.total(),
+                    terms,
+                };
+                write_data(data, response)
+            }
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/probe") {
+        let result = get_moves(query_args).and_then(|moves| {
+            let config = get_eval_config(query_args)?;
+            let null = get_flag(query_args, "null")?;
+            board
+                .with_moves(&moves, |line| {
+                    // With null the other side moves again, showing what they threaten
+                    let threat = if null {
+                        line.ensure_in_progress()?;
+                        let () = if line.is_in_check(line.turn) {
+                            Err(Error::new(
+                                ErrorCode::InvalidNullMove,
+                                format!("Invalid null move: {} is in check", line.turn.name()),
+                            ))
+                        } else {
+                            Ok(())
+                        }?;
+                        let undo = line.make_null_move();
+                        let mut search = search::Search::new(config);
+                        let threat = search.best_move(line, THREAT_DEPTH, None).map(|result| {
+                            let pv = line.san_line(&result.pv);
+                            LineData {
+                                mv: pv[0].clone(),
+                                score: result.score,
+                                pv,
+                            }
+                        });
+                        // The rest of the answer describes the line, not the pass
+                        line.unmake_null_move(undo);
+                        threat
+                    } else {
+                        None
+                    };
+                    Ok(ProbeResponseData {
+                        fen: line.to_fen(),
+                        status: line.status(),
+                        eval: line.evaluate(&config),
+                        threat,
+                    })
+                })
+                .and_then(|data| data)
+        });
+        match result {
+            Ok(data) => write_data(data, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/mate") {
+        let result = get_mate_moves(query_args).and_then(|moves| {
+            board.ensure_in_progress()?;
+            Ok(board.mating_moves(moves))
+        });
+        match result {
+            Ok(mates) => write_mate(board, mates, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/analyze") && query_args.contains_key("lines") {
+        let result = get_search_limits(query_args).and_then(|(depth, budget)| {
+            let lines = get_lines(query_args)?;
+            let mut search = search::Search::new(get_eval_config(query_args)?);
+            board.ensure_in_progress()?;
+            match search.best_lines(board, depth, budget, lines) {
+                results if results.is_empty() => Err(no_moves()),
+                results => Ok(results),
+            }
+        });
+        match result {
+            Ok(results) => write_lines(board, results, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/analyze") {
+        let result = get_search_limits(query_args).and_then(|(depth, budget)| {
+            let mut search = search::Search::new(get_eval_config(query_args)?);
+            board.ensure_in_progress()?;
+            search.best_move(board, depth, budget).ok_or_else(no_moves)
+        });
+        match result {
+            Ok(result) => write_analysis(board, result, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/ai") {
+        let result = get_search_limits(query_args).and_then(|(depth, budget)| {
+            let config = get_eval_config(query_args)?;
+            // A level picks the depth itself, overriding any depth argument
+            let (mut search, depth) = match get_level(query_args)? {
+                None => (search::Search::new(config), depth),
+                Some(params) => (
+                    search::Search::with_params(config, params, get_seed(query_args, board)?),
+                    params.depth,
+                ),
+            };
+            board.ensure_in_progress()?;
+            let found = match board.endgame_move() {
+                Some(mv) => Some(search::SearchResult::technique(board, mv)),
+                None => search.best_move(board, depth, budget),
+            };
+            match found {
+                None => Err(no_moves()),
+                Some(result) => board
+                    .step(result.best.from, result.best.to, result.best.promote)
+                    .map(|()| result),
+            }
+        });
+        match result {
+            Ok(result) => write_ai_move(board, result, discard_warning(board, upcoming), response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/random") {
+        let result = get_seed(query_args, board).and_then(|seed| {
+            board.ensure_in_progress()?;
+            match board.random_move(&mut random::SplitMix64::new(seed)) {
+                None => Err(no_moves()),
+                Some(mv) => board.step(mv.from, mv.to, mv.promote),
+            }
+        });
+        match result {
+            Ok(()) => write_moved_board(board, upcoming, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/setup") {
+        let result = if query_args.contains_key("done") {
+            match query_args.get("color") {
+                None => Ok(None),
+                Some(raw) => color_from_string(raw).map(Some),
+            }
+            .and_then(|turn| board.finish_setup(turn))
+        } else {
+            board.begin_setup();
+            Ok(())
+        };
+        match result {
+            Ok(()) => write_board(board, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/place") || path.eq("/clear") {
+        let result = get_square(query_args, "at").and_then(|at| {
+            let piece = if path.eq("/place") {
+                Some(get_piece(query_args)?)
+            } else {
+                None
+            };
+            board.place(at, piece)
+        });
+        match result {
+            Ok(()) => write_board(board, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/turn") {
+        match get_color(query_args).and_then(|color| board.set_turn(color)) {
+            Ok(()) => write_board(board, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/claim_draw") {
+        match board.claim_draw() {
+            Ok(()) => write_board(board, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/seed") {
+        let result = match query_args.get("value") {
+            None => Err(missing_arg("value")),
+            Some(raw) => raw
+                .parse::<u64>()
+                .map_err(|_| invalid_arg(format!("Invalid seed {}", raw))),
+        };
+        match result {
+            Ok(seed) => {
+                board.seed(seed);
+                write_data(SeedResponseData { seed }, response)
+            }
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/time_control") {
+        let result = get_time_control(query_args)
+            .and_then(|(base, increment)| board.set_time_control(base, increment));
+        match result {
+            Ok(()) => write_board(board, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/takeback") {
+        let result = get_color(query_args).and_then(|color| {
+            if get_flag(query_args, "approve")? {
+                board.approve_takeback(color)
+            } else {
+                board.request_takeback(color)
+            }
+        });
+        match result {
+            Ok(()) => write_board(board, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else if path.eq("/resign") || path.eq("/offer_draw") || path.eq("/accept_draw") {
+        let result = get_color(query_args).and_then(|color| match path.as_str() {
+            "/resign" => board.resign(color),
+            "/offer_draw" => board.offer_draw(color),
+            _ => board.accept_draw(color),
+        });
+        match result {
+            Ok(()) => write_board(board, response),
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                write_err(e, response)
+            }
+        };
+    } else {
+        write_not_found(
+            Error::new(ErrorCode::NotFound, "Unknown path".to_string()),
+            response,
+        );
+    }
+    true
+}
+
+// Tokens stand in for a player, so they stay out of the log
+fn redact_arg<'a>(key: &str, value: &'a str) -> &'a str {
+    if key == "token" {
+        "[redacted]"
+    } else {
+        value
+    }
+}
+
+// A request read off the listener, waiting for the thread that owns the games
+struct PendingRequest {
+    stream: TcpStream,
+    request: Request,
+    start: Instant,
+}
+
+fn new_response(request: &Request, config: &ServerConfig) -> Response {
+    let origin = request.headers.get("origin").map(String::as_str);
+    Response {
+        cors: cors_headers(config.allowed_origins.as_deref(), origin),
+        bytes: Vec::new(),
+    }
+}
+
+// None when there is nothing to answer: the client went away, or sent a request
+// line too broken to route, which gets its 400 here
+fn accept_request(stream: &TcpStream, config: &ServerConfig) -> Option<Request> {
+    stream.set_read_timeout(Some(READ_TIMEOUT)).ok()?;
+    let (head, body) = read_request(stream)?;
+    match parse_request(&head, body) {
+        Ok(request) => Some(request),
+        Err(e) => {
+            let origin = parse_headers(&head).get("origin").cloned();
+            let mut response = Response {
+                cors: cors_headers(config.allowed_origins.as_deref(), origin.as_deref()),
+                bytes: Vec::new(),
+            };
+            let status = match e.code {
+                ErrorCode::PayloadTooLarge => "413 Payload Too Large",
+                _ => "400 Bad Request",
+            };
+            log(
+                LogLevel::Info,
+                format_args!("status={} {}", &status[..3], e),
+            );
+            response.bytes = error_res(&response.cors, &e, status).into_bytes();
+            let mut stream = stream;
+            let _ = stream.write_all(&response.bytes);
+            None
+        }
+    }
+}
+
+// Each connection is read on a thread of its own, so a client that sends nothing
+// holds up nobody else
+fn accept_requests(
+    listener: TcpListener,
+    config: ServerConfig,
+    worker: mpsc::Sender<PendingRequest>,
+) {
+    let config = Arc::new(config);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log(LogLevel::Error, format_args!("Error: {}", e));
+                std::thread::sleep(ACCEPT_RETRY);
+                continue;
+            }
+        };
+        let (config, worker) = (Arc::clone(&config), worker.clone());
+        std::thread::spawn(move || read_connection(stream, &config, &worker));
+    }
+}
+
+// Answers the routes that read no game right away, so they never queue behind a
+// search. Everything else goes to the worker.
+fn read_connection(
+    stream: TcpStream,
+    config: &ServerConfig,
+    worker: &mpsc::Sender<PendingRequest>,
+) {
+    let start = Instant::now();
+    let request = match accept_request(&stream, config) {
+        None => return,
+        Some(request) => request,
+    };
+    if is_status_route(&request.path) {
+        let mut response = new_response(&request, config);
+        handle_status_request(&request, &mut response);
+        reply(&stream, &request, &response, start, None);
+    } else if let Err(mpsc::SendError(pending)) = worker.send(PendingRequest {
+        stream,
+        request,
+        start,
+    }) {
+        // The worker only hangs up on shutdown
+        refuse_shutting_down(pending, config);
+    }
+}
+
+// Requests queued behind the shutdown get a 503 rather than a closed connection
+fn drain_on_shutdown(receiver: &mpsc::Receiver<PendingRequest>, config: &ServerConfig) {
+    while let Ok(pending) = receiver.try_recv() {
+        refuse_shutting_down(pending, config);
+    }
+}
+
+fn refuse_shutting_down(pending: PendingRequest, config: &ServerConfig) {
+    let mut response = new_response(&pending.request, config);
+    let e = Error::new(ErrorCode::ShuttingDown, "Shutting down".to_string());
+    response.bytes = error_res(&response.cors, &e, "503 Service Unavailable").into_bytes();
+    reply(
+        &pending.stream,
+        &pending.request,
+        &response,
+        pending.start,
+        None,
+    );
+}
+
+// Returns false once the server should stop accepting connections
+fn serve(
+    games: &mut Games,
+    pending: PendingRequest,
+    config: &ServerConfig,
+    limiter: &mut RateLimiter,
+) -> bool {
+    let PendingRequest {
+        stream,
+        request,
+        start,
+    } = pending;
+    let mut response = new_response(&request, config);
+    // Flags fall between requests, so settle that before anything reads a game
+    for game in games.games.values_mut() {
+        game.board.check_flag();
+    }
+    let limited = match stream.peer_addr() {
+        Ok(peer) if is_expensive(&request) => limiter.check(peer.ip(), start).err(),
+        _ => None,
+    };
+    let running = match limited {
+        Some(retry_after) => {
+            write_too_many_requests(retry_after, &mut response);
+            true
+        }
+        None => handle_games_request(
+            games,
+            &request,
+            config.shutdown_token.as_deref(),
+            &mut response,
+        ),
+    };
+    // Only built when wanted, since the FEN costs a pass over the board
+    let fen = log_enabled(LogLevel::Debug).then(|| {
+        get_game_id(&request.query_args)
+            .ok()
+            .and_then(|id| games.games.get(&id))
+            .map_or("-".to_string(), |game| game.board.to_fen())
+    });
+    reply(&stream, &request, &response, start, fen);
+    running
+}
+
+// Every route goes through here so each request gets one log line
+fn reply(
+    mut stream: &TcpStream,
+    request: &Request,
+    response: &Response,
+    start: Instant,
+    fen: Option<String>,
+) {
+    // A client that hangs up before reading the reply has nothing left to tell
+    let _ = stream
+        .write_all(&response.bytes)
+        .and_then(|()| stream.flush());
+    let status = String::from_utf8_lossy(&response.bytes)
+        .split(' ')
+        .nth(1)
+        .unwrap_or("-")
+        .to_string();
+    let mut args: Vec<String> = request
+        .query_args
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, redact_arg(key, value)))
+        .collect();
+    args.sort();
+    log(
+        LogLevel::Info,
+        format_args!(
+            "method={} path={} status={} elapsed_ms={:.3} args={}",
+            request.method,
+            request.path,
+            status,
+            start.elapsed().as_secs_f64() * 1000.0,
+            if args.is_empty() {
+                "-".to_string()
+            } else {
+                args.join("&")
+            }
+        ),
+    );
+    if log_enabled(LogLevel::Debug) {
+        let parsed: BTreeMap<&String, &str> = request
+            .query_args
+            .iter()
+            .map(|(key, value)| (key, redact_arg(key, value)))
+            .collect();
+        log(
+            LogLevel::Debug,
+            format_args!(
+                "query_args={:?} headers={} body_bytes={} fen={}",
+                parsed,
+                request.headers.len(),
+                request.body.len(),
+                fen.as_deref().unwrap_or("-")
+            ),
+        );
+    }
+}
+
+// Plays random games to the end, checking every ply; game n uses seed + n so a
+// failure can be replayed on its own
+fn fuzz(games: u64, seed: u64) -> Result<(), String> {
+    for game in 0..games {
+        let game_seed = seed.wrapping_add(game);
+        let mut rng = random::SplitMix64::new(game_seed);
+        let mut board = Board::new();
+        let mut played: Vec<Move> = Vec::new();
+        let failure = loop {
+            if board.status() != Status::Ongoing {
+                break None;
+            }
+            let mv = match board.random_move(&mut rng) {
+                None => break Some("no legal moves in an ongoing game".to_string()),
+                Some(mv) => mv,
+            };
+            played.push(mv);
+            if !board.all_legal_moves().contains(&mv) {
+                break Some(format!("{} is not a legal move", mv));
+            }
+            let stepped = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                board.step(mv.from, mv.to, mv.promote)
+            }));
+            match stepped {
+                Err(_) => break Some(format!("step panicked on {}", mv)),
+                Ok(Err(e)) => break Some(format!("step rejected {}: {}", mv, e)),
+                Ok(Ok(())) => (),
+            }
+            if let Err(e) = board.validate_invariants() {
+                break Some(e);
+            }
+            // Checked here too since the assertion in step is compiled out of release builds
+            if board.is_in_check(board.turn.opposite()) {
+                break Some(format!(
+                    "{} left {} in check",
+                    mv,
+                    board.turn.opposite().name()
+                ));
+            }
+        };
+        if let Some(failure) = failure {
+            let moves: Vec<String> = played.iter().map(|mv| mv.to_string()).collect();
+            return Err(format!(
+                "Game {} (seed {}) failed after {} plies: {}\nMoves: {}\n{}",
+                game,
+                game_seed,
+                played.len(),
+                failure,
+                moves.join(" "),
+                board
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn run_fuzz(args: &[String]) {
+    let parsed = (
+        args.first().map_or(Ok(1000), |raw| raw.parse::<u64>()),
+        args.get(1)
+            .map_or(Ok(time_seed()), |raw| raw.parse::<u64>()),
+    );
+    let (games, seed) = match parsed {
+        (Ok(games), Ok(seed)) => (games, seed),
+        _ => {
+            eprintln!("Usage: chess fuzz [games] [seed]");
+            std::process::exit(2);
+        }
+    };
+    println!("Fuzzing {} games from seed {}", games, seed);
+    match fuzz(games, seed) {
+        Ok(()) => println!("All {} games passed", games),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// The benchmark only sees this and BENCH_POSITIONS
+pub fn perft(fen: &str, depth: u8) -> Result<u64, String> {
+    Board::from_fen(fen)
+        .map(|mut board| board.perft(depth))
+        .map_err(|e| e.message)
+}
+
+// Name, FEN, depth and the node count it has to reach
+pub const BENCH_POSITIONS: [(&str, &str, u8, u64); 5] = [
+    (
+        "startpos",
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+        5,
+        4_865_609,
+    ),
+    (
+        "kiwipete",
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+        4,
+        4_085_603,
+    ),
+    (
+        "endgame",
+        "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+        5,
+        674_624,
+    ),
+    (
+        "promotions",
+        "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+        4,
+        422_333,
+    ),
+    (
+        "middlegame",
+        "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+        4,
+        2_103_487,
+    ),
+];
+
+pub fn run_server() {
+    match get_log_level() {
+        Ok(level) => LOG_LEVEL.store(level as u8, Ordering::Relaxed),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let save_path = get_save_path();
+    let mut games = save_path
+        .as_deref()
+        .map_or_else(|| Games::new(Board::new()), resume_games);
+    let mut saved: HashMap<u64, String> = games
+        .games
+        .iter()
+        .map(|(&id, game)| (id, game_to_save(game)))
+        .collect();
+    let (mut limiter, autosave_interval) =
+        match get_rate_limiter().and_then(|limiter| Ok((limiter, get_autosave_interval()?))) {
+            Ok(settings) => settings,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        };
+    let listener = match get_bind_addr().and_then(|addr| {
+        TcpListener::bind(addr).map_err(|e| format!("Could not bind {}: {}", addr, e))
+    }) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    log(
+        LogLevel::Info,
+        format_args!("Listening on {}", listener.local_addr().unwrap()),
+    );
+    let config = ServerConfig {
+        // /shutdown is disabled unless a token is configured
+        shutdown_token: std::env::var("CHESS_SHUTDOWN_TOKEN").ok(),
+        allowed_origins: get_allowed_origins(),
+    };
+
+    // This thread owns the games and the acceptor feeds it; returning ends the
+    // process, acceptor included
+    let (sender, receiver) = mpsc::channel();
+    let acceptor_config = config.clone();
+    std::thread::spawn(move || accept_requests(listener, acceptor_config, sender));
+    loop {
+        let running = match receiver.recv_timeout(autosave_interval) {
+            Ok(pending) => serve(&mut games, pending, &config, &mut limiter),
+            // A flag can fall with nobody asking, and that ends the game too
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                for game in games.games.values_mut() {
+                    game.board.check_flag();
+                }
+                true
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => false,
+        };
+        if let Some(path) = &save_path {
+            autosave(&games, path, &mut saved);
+        }
+        if !running {
+            break;
+        }
+    }
+    log(LogLevel::Info, format_args!("Shutting down"));
+    drain_on_shutdown(&receiver, &config);
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,971 @@
+use super::*;
+
+fn legal(fen: &str, uci: &str) -> bool {
+    Board::from_fen(fen).unwrap().parse_uci(uci).is_ok()
+}
+
+// Runs `check` on every position of `games` random games from the start, game n
+// seeded with seed + n. The position is passed mutably for checks that make and
+// unmake moves on it, which must leave it as it was.
+fn for_random_positions(seed: u64, games: u64, mut check: impl FnMut(&mut Board)) {
+    for game in 0..games {
+        let mut rng = random::SplitMix64::new(seed.wrapping_add(game));
+        let mut board = Board::new();
+        loop {
+            check(&mut board);
+            if board.status() != Status::Ongoing {
+                break;
+            }
+            let mv = board.random_move(&mut rng).unwrap();
+            board.step(mv.from, mv.to, mv.promote).unwrap();
+        }
+    }
+}
+
+#[test]
+fn perft_matches_reference_counts() {
+    for (name, fen, depth, expected) in BENCH_POSITIONS.iter() {
+        assert_eq!(perft(fen, *depth), Ok(*expected), "{}", name);
+    }
+}
+
+#[test]
+fn malformed_request_line_is_a_bad_request() {
+    for head in ["garbage\r\n\r\n", "GET\r\n\r\n", "\r\n\r\n"] {
+        let code = parse_request(head, Vec::new()).err().map(|e| e.code);
+        assert_eq!(code, Some(ErrorCode::BadRequest), "{:?}", head);
+    }
+    let request = parse_request("GET /move?from=12&to=28 HTTP/1.1\r\n\r\n", Vec::new()).unwrap();
+    assert_eq!(request.method, "GET");
+    assert_eq!(request.path, "/move");
+    assert_eq!(request.query_args.get("to").map(String::as_str), Some("28"));
+}
+
+#[test]
+fn parse_headers_lowercases_names_and_keeps_colons_in_values() {
+    let head = "GET / HTTP/1.1\r\n\
+Host: localhost:8080\r\n\
+Origin: http://example.com:3000\r\n\
+X-Time:  12:30:00 \r\n\
+Accept: text/csv\r\n\
+ACCEPT: application/json\r\n\
+no colon here\r\n\
+\r\n";
+    let headers = parse_headers(head);
+    let get = |name: &str| headers.get(name).map(String::as_str);
+    assert_eq!(get("host"), Some("localhost:8080"));
+    assert_eq!(get("origin"), Some("http://example.com:3000"));
+    assert_eq!(get("x-time"), Some("12:30:00"));
+    assert_eq!(get("accept"), Some("text/csv, application/json"));
+    assert_eq!(headers.len(), 4);
+}
+
+#[test]
+fn from_to_rejects_bad_indices() {
+    let args = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    };
+    let code = |pairs: &[(&str, &str)]| get_from_to(&args(pairs)).err().map(|e| e.code);
+    assert_eq!(
+        code(&[("from", "64"), ("to", "0")]),
+        Some(ErrorCode::InvalidArgument)
+    );
+    assert_eq!(
+        code(&[("from", "e2"), ("to", "e4")]),
+        Some(ErrorCode::InvalidArgument)
+    );
+    assert_eq!(code(&[]), Some(ErrorCode::MissingArgument));
+    assert_eq!(
+        get_from_to(&args(&[("from", "12"), ("to", "28")])),
+        Ok((Location { x: 4, y: 1 }, Location { x: 4, y: 3 }))
+    );
+}
+
+#[test]
+fn setup_board_without_a_king_reports_no_moves() {
+    let mut board = Board::new();
+    board.begin_setup();
+    board.place(Location { x: 4, y: 0 }, None).unwrap();
+    assert_eq!(board.snapshot().status, Status::Ongoing);
+    assert!(!board.has_legal_moves());
+    assert!(board.all_legal_moves().is_empty());
+    assert!(board.legal_moves_from(Location { x: 4, y: 1 }).is_empty());
+}
+
+#[test]
+fn failed_finish_setup_leaves_the_board_untouched() {
+    let mut board =
+        Board::from_fen("rnbqkbnr/ppp1pppp/8/3pP3/8/8/PPPP1PPP/RNBQKBNR w KQkq d6 5 20").unwrap();
+    board.begin_setup();
+    board.place(Location { x: 4, y: 7 }, None).unwrap();
+    board.place(Location { x: 7, y: 0 }, None).unwrap();
+    assert!(board.finish_setup(Some(piece::Color::Black)).is_err());
+    assert!(board.setup);
+    assert_eq!(board.turn, piece::Color::White);
+    assert_eq!(board.castling.to_fen(), "KQkq");
+    assert_eq!(board.en_passant, Some(Location { x: 3, y: 5 }));
+    assert_eq!(board.halfmove_clock, 5);
+    assert_eq!(board.fullmove_number, 20);
+    assert_eq!(board.zobrist(), board.compute_zobrist());
+}
+
+#[test]
+fn divide_gives_up_past_its_node_cap() {
+    let board = Board::new();
+    let divide = board.divide(3, 8_902).unwrap();
+    assert_eq!(divide.len(), 20);
+    assert_eq!(divide.iter().map(|(_, nodes)| nodes).sum::<u64>(), 8_902);
+    assert_eq!(board.divide(3, 8_901), None);
+}
+
+#[test]
+fn black_snapshot_runs_from_h8() {
+    use piece::{Color, Piece, Type};
+    let board = Board::from_fen("4k3/8/8/8/8/8/8/R3K3 w - - 0 1").unwrap();
+    let cell = |square: &str, piece: Option<Piece>| CellView {
+        square: square.parse().unwrap(),
+        piece,
+    };
+    let white = board.snapshot().cells;
+    assert_eq!(
+        white[0],
+        cell("a1", Piece::new_opt(Type::Rook, Color::White))
+    );
+    let black = board.snapshot_oriented(Color::Black).cells;
+    assert_eq!(black[0], cell("h8", None));
+    assert_eq!(
+        black[3],
+        cell("e8", Piece::new_opt(Type::King, Color::Black))
+    );
+    assert_eq!(
+        black[63],
+        cell("a1", Piece::new_opt(Type::Rook, Color::White))
+    );
+    assert_eq!(
+        serde_json::to_value(black[3]).unwrap(),
+        serde_json::json!({"square": "e8", "color": "black", "type": "king"})
+    );
+    assert_eq!(
+        serde_json::to_value(black[0]).unwrap(),
+        serde_json::json!({"square": "h8"})
+    );
+}
+
+// A fresh directory under the system temp dir for tests that touch save files
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("chess-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn save_paths_carry_the_game_id() {
+    let base = Path::new("saves/game.pgn");
+    assert_eq!(game_save_path(base, DEFAULT_GAME_ID), base);
+    assert_eq!(game_save_path(base, 12), Path::new("saves/game-12.pgn"));
+    assert_eq!(
+        saved_game_id(base, Path::new("saves/game-12.pgn")),
+        Some(12)
+    );
+    assert_eq!(saved_game_id(base, base), None);
+    assert_eq!(saved_game_id(base, Path::new("saves/game-1.pgn")), None);
+    assert_eq!(saved_game_id(base, Path::new("saves/game-012.pgn")), None);
+    assert_eq!(saved_game_id(base, Path::new("saves/game-12.tmp")), None);
+}
+
+#[test]
+fn every_game_is_saved_and_resumed_with_its_tokens() {
+    let base = scratch_dir("resume").join("game.pgn");
+    let mut games = Games::new(Board::new());
+    let (id, tokens) = games.create(Board::new()).unwrap();
+    let tokens = (tokens.white.clone(), tokens.black.clone());
+    let game = games.get_mut(id).unwrap();
+    game.board
+        .step(Location { x: 4, y: 1 }, Location { x: 4, y: 3 }, None)
+        .unwrap();
+    let mut saved = HashMap::new();
+    autosave(&games, &base, &mut saved);
+    assert_eq!(saved.len(), 2);
+
+    let resumed = resume_games(&base);
+    assert_eq!(resumed.next_id, id + 1);
+    let game = &resumed.games[&id];
+    assert_eq!(game.board.to_fen(), games.games[&id].board.to_fen());
+    let resumed_tokens = game.tokens.as_ref().unwrap();
+    assert_eq!(
+        (resumed_tokens.white.clone(), resumed_tokens.black.clone()),
+        tokens
+    );
+    assert!(resumed.games[&DEFAULT_GAME_ID].tokens.is_none());
+
+    // A save that can't be read back is left out, and its id stays taken
+    fs::write(game_save_path(&base, 7), "1. e4 e5 2. Qxf7 {truncated").unwrap();
+    let resumed = resume_games(&base);
+    assert!(!resumed.games.contains_key(&7));
+    assert_eq!(resumed.next_id, 8);
+    fs::remove_dir_all(base.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn status_routes_need_no_game() {
+    let answer = |head: &str| {
+        let request = parse_request(head, Vec::new()).unwrap();
+        assert!(is_status_route(&request.path));
+        let mut response = Response {
+            cors: String::new(),
+            bytes: Vec::new(),
+        };
+        handle_status_request(&request, &mut response);
+        String::from_utf8(response.bytes).unwrap()
+    };
+    assert!(answer("GET /healthz HTTP/1.1\r\n\r\n").ends_with(r#"{"status":"ok"}"#));
+    assert!(answer("GET /version HTTP/1.1\r\n\r\n").contains(env!("CARGO_PKG_VERSION")));
+    assert!(answer("POST /healthz HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+    assert!(!is_status_route("/game"));
+}
+
+#[test]
+fn distances_between_corners() {
+    let square = |name: &str| name.parse::<Location>().unwrap();
+    let (a1, h1, a8, h8) = (square("a1"), square("h1"), square("a8"), square("h8"));
+    assert_eq!(a1.distance_manhattan(h8), 14);
+    assert_eq!(h8.distance_manhattan(a1), 14);
+    assert_eq!(a1.distance_manhattan(h1), 7);
+    assert_eq!(h1.distance_manhattan(a8), 14);
+    assert_eq!(a1.distance_manhattan(a1), 0);
+    assert_eq!(a1.distance_chebyshev(h8), 7);
+    assert_eq!(h1.distance_chebyshev(a8), 7);
+    assert_eq!(a1.distance_chebyshev(h1), 7);
+    assert_eq!(square("b2").distance_chebyshev(a1), 1);
+    assert_eq!(square("b2").distance_manhattan(a1), 2);
+}
+
+#[test]
+fn files_ranks_and_offsets_at_the_corners() {
+    let square = |name: &str| name.parse::<Location>().unwrap();
+    let (a1, h1, a8, h8) = (square("a1"), square("h1"), square("a8"), square("h8"));
+    assert_eq!((a1.file(), a1.rank()), ('a', 1));
+    assert_eq!((h8.file(), h8.rank()), ('h', 8));
+    assert_eq!((h1.file(), a8.rank()), ('h', 8));
+    assert_eq!(a1.offset(-1, 0), None);
+    assert_eq!(a1.offset(0, -1), None);
+    assert_eq!(h8.offset(1, 1), None);
+    assert_eq!(h1.offset(1, 0), None);
+    assert_eq!(a8.offset(0, 1), None);
+    assert_eq!(a1.offset(7, 7), Some(h8));
+    assert_eq!(h8.offset(-7, -7), Some(a1));
+    assert_eq!(a1.offset(7, 0), Some(h1));
+    assert_eq!(a1.offset(0, 0), Some(a1));
+}
+
+// Plays the side to move with strong_move against the depth-3 search, returning the
+// final status and how many plies it took, or None if it ran out of plies
+fn play_out(
+    fen: &str,
+    max_plies: usize,
+    mut strong_move: impl FnMut(&mut Board) -> Move,
+) -> Option<(Status, usize)> {
+    let mut board = Board::from_fen(fen).unwrap();
+    let strong = board.turn;
+    let mut search = search::Search::new(EvalConfig::default());
+    for ply in 0..max_plies {
+        let mv = if board.turn == strong {
+            strong_move(&mut board)
+        } else {
+            search.best_move(&mut board, 3, None).unwrap().best
+        };
+        board.step(mv.from, mv.to, mv.promote).unwrap();
+        if board.status() != Status::Ongoing {
+            return Some((board.status(), ply + 1));
+        }
+    }
+    None
+}
+
+#[test]
+fn rook_technique_mates_a_lone_king() {
+    for fen in [
+        "4k3/8/8/8/8/8/8/R3K3 w - - 0 1",
+        "8/8/4k3/8/8/8/8/R3K3 w - - 0 1",
+        "8/8/8/3k4/8/8/8/KR6 w - - 0 1",
+        "8/2k5/8/8/8/8/6R1/7K w - - 0 1",
+        "7K/8/8/8/3k4/8/8/r7 b - - 0 1",
+    ] {
+        let outcome = play_out(fen, 29, |board| board.endgame_move().unwrap());
+        assert!(
+            matches!(outcome, Some((Status::Checkmate, _))),
+            "{}: {:?}",
+            fen,
+            outcome
+        );
+    }
+}
+
+#[test]
+fn search_mates_with_a_queen_instead_of_stalemating() {
+    // Qb6 stalemates straight away, and the lone king starts in the corner
+    for fen in [
+        "k7/8/8/1Q6/8/8/8/7K w - - 0 1",
+        "8/8/4k3/8/8/8/8/3QK3 w - - 0 1",
+        "7k/8/8/8/8/8/q7/7K b - - 0 1",
+    ] {
+        let mut search = search::Search::new(EvalConfig::default());
+        let outcome = play_out(fen, 40, |board| {
+            search.best_move(board, 3, None).unwrap().best
+        });
+        assert!(
+            matches!(outcome, Some((Status::Checkmate, _))),
+            "{}: {:?}",
+            fen,
+            outcome
+        );
+    }
+}
+
+#[test]
+fn pawn_never_pushes_onto_an_occupied_square() {
+    let friend = "4k3/8/8/8/8/4N3/4P3/4K3 w - - 0 1";
+    assert!(!legal(friend, "e2e3"));
+    assert!(!legal(friend, "e2e4"));
+    let enemy = "4k3/8/8/8/8/4n3/4P3/4K3 w - - 0 1";
+    assert!(!legal(enemy, "e2e3"));
+    assert!(!legal(enemy, "e2e4"));
+}
+
+#[test]
+fn pawn_captures_only_enemies_on_the_diagonal() {
+    assert!(legal("4k3/8/8/8/8/3n4/4P3/4K3 w - - 0 1", "e2d3"));
+    assert!(!legal("4k3/8/8/8/8/3N4/4P3/4K3 w - - 0 1", "e2d3"));
+    assert!(!legal("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1", "e2d3"));
+    let en_passant = "4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1";
+    assert!(legal(en_passant, "e5d6"));
+    assert!(!legal(en_passant, "e5f6"));
+}
+
+#[test]
+fn en_passant_that_uncovers_the_king_is_rejected() {
+    let fen = "k7/8/8/KPp4r/8/8/8/8 w - c6";
+    assert!(!legal(fen, "b5c6"));
+    assert!(legal(fen, "b5b6"));
+    // Made anyway, the capture takes c5 and unmaking puts that pawn back there
+    let mut board = Board::from_fen(fen).unwrap();
+    let before = board.to_fen();
+    let mv: Move = "b5c6".parse().unwrap();
+    let undo = board.make_move(mv);
+    assert_eq!(board.get(Location { x: 2, y: 4 }), None);
+    assert!(board.is_in_check(piece::Color::White));
+    board.unmake_move(mv, undo);
+    assert_eq!(
+        board.get(Location { x: 2, y: 4 }),
+        piece::Piece::new_opt(piece::Type::Pawn, piece::Color::Black)
+    );
+    assert_eq!(board.get(Location { x: 2, y: 5 }), None);
+    assert_eq!(board.to_fen(), before);
+}
+
+#[test]
+fn san_disambiguates_only_between_legal_rivals() {
+    let san = |fen: &str, uci: &str| {
+        let board = Board::from_fen(fen).unwrap();
+        let mv = board.parse_uci(uci).unwrap();
+        board.san(mv, &board.all_legal_moves())
+    };
+    assert_eq!(san("rn2k3/8/5n2/8/8/8/8/4K3 b - - 0 1", "b8d7"), "Nbd7");
+    assert_eq!(san("4k3/8/8/6N1/8/8/8/4K1N1 w - - 0 1", "g1f3"), "N1f3");
+    assert_eq!(san("7K/8/k7/8/4Q2Q/8/8/7Q w - - 0 1", "h4e1"), "Qh4e1");
+    // The c3 knight could reach e2 too, but it is pinned to the king
+    assert_eq!(san("4k3/8/8/b7/8/2N5/8/4K1N1 w - - 0 1", "g1e2"), "Ne2");
+    assert_eq!(san("4k3/8/8/8/8/8/8/R4RK1 w - - 0 1", "a1d1"), "Rad1");
+}
+
+#[test]
+fn rook_stops_at_the_first_enemy_piece() {
+    let board = Board::from_fen("4k3/8/8/8/p7/8/8/R3K3 w - - 0 1").unwrap();
+    let mut up_the_file: Vec<String> = board
+        .legal_moves_from(Location { x: 0, y: 0 })
+        .into_iter()
+        .filter(|mv| mv.to.x == 0)
+        .map(|mv| mv.to.to_string())
+        .collect();
+    up_the_file.sort();
+    assert_eq!(up_the_file, ["a2", "a3", "a4"]);
+    assert!(board.is_capture("a1a4".parse().unwrap()));
+}
+
+#[test]
+fn no_legal_moves_is_stalemate_or_mate_by_check() {
+    let corner = Board::from_fen("k7/8/1Q6/8/8/8/8/7K b - - 0 1").unwrap();
+    assert_eq!(corner.status(), Status::Stalemate);
+    let back_rank = Board::from_fen("3R2k1/5ppp/8/8/8/8/8/6K1 b - - 0 1").unwrap();
+    assert_eq!(back_rank.status(), Status::Checkmate);
+}
+
+#[test]
+fn incremental_zobrist_matches_a_recompute() {
+    // En passant, a promotion with capture and castling on both sides
+    let line = "e2e4 d7d5 e4d5 c7c5 d5c6 g8f6 c6b7 e7e6 b7a8q f8e7 g1f3 e8g8 f1e2 d8d5 e1g1";
+    let mut board = Board::new();
+    let mut hashes = vec![board.zobrist()];
+    for uci in line.split(' ') {
+        let mv = board.parse_uci(uci).unwrap();
+        board.step(mv.from, mv.to, mv.promote).unwrap();
+        assert_eq!(board.zobrist(), board.compute_zobrist(), "after {}", uci);
+        hashes.push(board.zobrist());
+    }
+    while board.ply() > 0 {
+        board.undo().unwrap();
+        hashes.pop();
+        assert_eq!(board.zobrist(), board.compute_zobrist());
+        assert_eq!(Some(&board.zobrist()), hashes.last());
+    }
+}
+
+// After 1.e4 d5 2.Nf3 Nc6, a position with plenty of transpositions and captures
+const SEARCH_FEN: &str = "r1bqkbnr/ppp1pppp/2n5/3p4/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3";
+
+// Nodes a fresh search of SEARCH_FEN visits to the given depth
+fn search_nodes(search: search::Search, depth: u8) -> u64 {
+    let mut search = search;
+    let mut board = Board::from_fen(SEARCH_FEN).unwrap();
+    search.best_move(&mut board, depth, None).unwrap().nodes
+}
+
+#[test]
+fn transposition_table_saves_nodes() {
+    let with = search_nodes(search::Search::new(EvalConfig::default()), 4);
+    let without = search_nodes(
+        search::Search::new(EvalConfig::default()).without_table(),
+        4,
+    );
+    assert!(
+        with < without,
+        "{} nodes with the table, {} without",
+        with,
+        without
+    );
+}
+
+#[test]
+fn move_ordering_saves_nodes() {
+    let ordered = search_nodes(search::Search::new(EvalConfig::default()), 4);
+    let unordered = search_nodes(
+        search::Search::new(EvalConfig::default()).without_ordering(),
+        4,
+    );
+    assert!(
+        ordered * 4 < unordered,
+        "{} nodes ordered, {} unordered",
+        ordered,
+        unordered
+    );
+}
+
+#[test]
+fn quiescence_sees_the_recapture_past_the_horizon() {
+    // Qxe5+ wins a pawn at depth 1, and loses the queen to dxe5 a ply later
+    let fen = "4k3/8/3p4/4p3/8/8/4Q3/4K3 w - - 0 1";
+    let best = |quiescence: bool| {
+        let params = search::SearchParams {
+            depth: 1,
+            quiescence,
+            noise: 0,
+        };
+        let mut search = search::Search::with_params(EvalConfig::default(), params, 0);
+        let mut board = Board::from_fen(fen).unwrap();
+        search.best_move(&mut board, 1, None).unwrap().best
+    };
+    let grab: Move = "e2e5".parse().unwrap();
+    assert_eq!(best(false), grab);
+    assert_ne!(best(true), grab);
+}
+
+#[test]
+fn square_colors() {
+    let square = |name: &str| name.parse::<Location>().unwrap();
+    assert!(square("a1").is_dark());
+    assert!(!square("h1").is_dark());
+    assert!(!square("a8").is_dark());
+    assert!(square("h8").is_dark());
+}
+
+#[test]
+fn step_errors_name_the_piece_and_squares() {
+    let error = |fen: &str, from: &str, to: &str| {
+        let mut board = Board::from_fen(fen).unwrap();
+        let e = board
+            .step(from.parse().unwrap(), to.parse().unwrap(), None)
+            .unwrap_err();
+        (e.code, e.message)
+    };
+    let start = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+    assert_eq!(
+        error(start, "b1", "e4"),
+        (
+            ErrorCode::InvalidMove,
+            "Invalid move: wN from b1 cannot reach e4".to_string()
+        )
+    );
+    assert_eq!(
+        error(start, "d1", "d3"),
+        (
+            ErrorCode::InvalidMove,
+            "Invalid move: wQ from d1 to d3 is blocked by wP on d2".to_string()
+        )
+    );
+    assert_eq!(
+        error(start, "e7", "e5"),
+        (
+            ErrorCode::NotYourTurn,
+            "Not your turn: bP at e7 but it is white to move".to_string()
+        )
+    );
+    assert_eq!(
+        error(start, "e4", "e5"),
+        (ErrorCode::NoPiece, "No piece at e4".to_string())
+    );
+    assert_eq!(
+        error("4k3/8/8/8/8/8/3b4/R3K3 w - - 0 1", "a1", "a2"),
+        (
+            ErrorCode::KingInCheck,
+            "King in check: wR from a1 to a2 leaves the white king in check".to_string()
+        )
+    );
+    assert_eq!(
+        error("4k3/8/8/8/8/8/8/R3K2r w Q - 0 1", "e1", "c1"),
+        (
+            ErrorCode::InvalidMove,
+            "Invalid move: wK from e1 cannot castle to c1".to_string()
+        )
+    );
+}
+
+#[test]
+fn halfmove_clock_resets_only_on_irreversible_moves() {
+    let fen = "r3k2r/8/8/3pP3/8/8/8/R3K2R w KQkq d6 5 10";
+    let after = |uci: &str| {
+        let mut board = Board::from_fen(fen).unwrap();
+        let mv = board.parse_uci(uci).unwrap();
+        let irreversible = board.is_irreversible(&mv);
+        board.step(mv.from, mv.to, mv.promote).unwrap();
+        (irreversible, board.halfmove_clock)
+    };
+    // En passant takes nothing on its destination square but is still a capture
+    assert_eq!(after("e5d6"), (true, 0));
+    assert_eq!(after("a1a8"), (true, 0));
+    assert_eq!(after("e5e6"), (true, 0));
+    assert_eq!(after("e1g1"), (false, 6));
+    assert_eq!(after("e1c1"), (false, 6));
+    assert_eq!(after("a1a2"), (false, 6));
+    assert_eq!(after("e1f1"), (false, 6));
+}
+
+#[test]
+fn square_attacks_agree_with_making_the_move() {
+    for_random_positions(331, 20, |board| {
+        let us = board.turn;
+        let before = board.to_fen();
+        for mv in board.pseudo_legal_moves(us) {
+            let fast = board.keeps_king_safe(mv);
+            // Castling checks the squares the king crosses before it is generated
+            if castling_rook(board.get(mv.from).unwrap(), mv).is_some() {
+                assert!(fast, "{} {}", before, mv);
+                continue;
+            }
+            let undo = board.make_move(mv);
+            let king = bitboard::squares(board.bitboards.pieces(piece::Type::King, us))
+                .next()
+                .unwrap();
+            let slow = !board
+                .pseudo_legal_moves(us.opposite())
+                .iter()
+                .any(|reply| reply.to == king);
+            assert_eq!(board.is_square_attacked(king, us.opposite()), !slow);
+            board.unmake_move(mv, undo);
+            assert_eq!(fast, slow, "{} {}", before, mv);
+        }
+        assert_eq!(board.to_fen(), before);
+    });
+}
+
+#[test]
+fn undoing_past_a_repetition_withdraws_the_claim() {
+    let shuffle = |board: &mut Board, plies: usize| {
+        let line = ["g1f3", "g8f6", "f3g1", "f6g8"];
+        for uci in line.iter().cycle().take(plies) {
+            let mv = board.parse_uci(uci).unwrap();
+            board.step(mv.from, mv.to, mv.promote).unwrap();
+        }
+    };
+    let mut claimed = Board::new();
+    shuffle(&mut claimed, 8);
+    assert_eq!(claimed.repetitions(), 3);
+    assert_eq!(claimed.claim_draw(), Ok(()));
+
+    let mut board = Board::new();
+    shuffle(&mut board, 8);
+    board.undo().unwrap();
+    board.undo().unwrap();
+    assert_eq!(board.repetitions(), 2);
+    let code = board.claim_draw().err().map(|e| e.code);
+    assert_eq!(code, Some(ErrorCode::NoDrawToClaim));
+    // Playing the moves again brings the claim back
+    for uci in ["f3g1", "f6g8"] {
+        let mv = board.parse_uci(uci).unwrap();
+        board.step(mv.from, mv.to, mv.promote).unwrap();
+    }
+    assert_eq!(board.claim_draw(), Ok(()));
+}
+
+#[test]
+fn fen_clock_fields_default_and_round_trip() {
+    let short = Board::from_fen("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3").unwrap();
+    assert_eq!(short.halfmove_clock, 0);
+    assert_eq!(short.fullmove_number, 1);
+    let full = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3";
+    assert_eq!(Board::from_fen(full).unwrap().to_fen(), full);
+}
+
+#[test]
+fn insufficient_material_follows_fide() {
+    let insufficient = |fen| Board::from_fen(fen).unwrap().insufficient_material();
+    assert!(insufficient("8/8/4k3/8/8/8/8/4K3 w - - 0 1"));
+    assert!(insufficient("8/8/4k3/8/8/8/8/2B1K3 w - - 0 1"));
+    assert!(!insufficient("8/8/4k3/8/8/8/8/1N2KN2 w - - 0 1"));
+    assert!(!insufficient("8/8/4k3/8/8/8/8/2B1KN2 w - - 0 1"));
+    assert!(insufficient("8/8/4k3/8/8/4B3/8/2B1K3 w - - 0 1"));
+    assert!(insufficient("5b2/8/4k3/8/8/8/8/2B1K3 w - - 0 1"));
+    assert!(!insufficient("2b5/8/4k3/8/8/8/8/2B1K3 w - - 0 1"));
+}
+
+#[test]
+fn capturing_a_rook_revokes_its_castling_right() {
+    let mut board = Board::from_fen("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1").unwrap();
+    let mv = board.parse_uci("h1h8").unwrap();
+    board.step(mv.from, mv.to, mv.promote).unwrap();
+    assert_eq!(board.castling.to_fen(), "Qq");
+    let mut board = Board::from_fen("r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1").unwrap();
+    let mv = board.parse_uci("a8a1").unwrap();
+    board.step(mv.from, mv.to, mv.promote).unwrap();
+    assert_eq!(board.castling.to_fen(), "Kk");
+}
+
+// status() decides mate and stalemate with the short-circuiting check
+#[test]
+fn has_legal_moves_agrees_with_all_legal_moves() {
+    for_random_positions(377, 20, |board| {
+        assert_eq!(
+            board.has_legal_moves(),
+            !board.all_legal_moves().is_empty(),
+            "{}",
+            board.to_fen()
+        );
+    });
+}
+
+#[test]
+fn to_bytes_round_trips() {
+    for_random_positions(383, 20, |board| {
+        let loaded = Board::from_bytes(&board.to_bytes()).unwrap();
+        assert_eq!(loaded.to_fen(), board.to_fen());
+    });
+}
+
+#[test]
+fn load_bytes_takes_what_game_serves_as_bytes() {
+    let fen = "r3k2r/8/8/3pP3/8/8/8/R3K2R w KQkq d6 0 12";
+    let mut request = parse_request("POST /load_bytes HTTP/1.1\r\n\r\n", Vec::new()).unwrap();
+    request.body = Board::from_fen(fen).unwrap().to_bytes();
+    let mut response = Response {
+        cors: String::new(),
+        bytes: Vec::new(),
+    };
+    let mut board = Board::new();
+    handle_request(&mut board, &request, None, &mut response);
+    assert!(response.bytes.starts_with(b"HTTP/1.1 200"));
+    assert_eq!(board.to_fen(), fen);
+
+    request.body.pop();
+    response.bytes.clear();
+    handle_request(&mut board, &request, None, &mut response);
+    let response = String::from_utf8(response.bytes).unwrap();
+    assert!(
+        response.contains(r#""error":"invalid_body""#),
+        "{}",
+        response
+    );
+    assert_eq!(board.to_fen(), fen);
+}
+
+#[test]
+fn flipped_positions_are_symmetric() {
+    let config = EvalConfig::default();
+    for_random_positions(388, 20, |board| {
+        let mut flipped = board.flipped();
+        assert_eq!(
+            flipped.evaluate(&config),
+            -board.evaluate(&config),
+            "{}",
+            board.to_fen()
+        );
+        assert_eq!(
+            flipped.legal_moves().len(),
+            board.legal_moves().len(),
+            "{}",
+            board.to_fen()
+        );
+        assert_eq!(flipped.flipped().to_fen(), board.to_fen());
+    });
+}
+
+#[test]
+fn null_move_keeps_the_hash_and_unmakes() {
+    let fen = "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq e6 0 2";
+    let mut board = Board::from_fen(fen).unwrap();
+    let undo = board.make_null_move();
+    assert_eq!((board.turn, board.en_passant), (piece::Color::Black, None));
+    board.unmake_null_move(undo);
+    assert_eq!(board.to_fen(), fen);
+    for_random_positions(393, 20, |board| {
+        if board.is_in_check(board.turn) {
+            return;
+        }
+        let (fen, hash) = (board.to_fen(), board.zobrist());
+        let undo = board.make_null_move();
+        assert_eq!(board.zobrist(), board.compute_zobrist(), "{}", fen);
+        board.unmake_null_move(undo);
+        assert_eq!((board.to_fen(), board.zobrist()), (fen, hash));
+    });
+}
+
+// The square by square generator sees pawns per color, so mirroring the position
+// has to mirror its moves exactly
+#[test]
+fn flipped_positions_mirror_pseudo_legal_moves() {
+    let mirror = |loc: Location| Location {
+        x: loc.x,
+        y: 7 - loc.y,
+    };
+    for_random_positions(398, 20, |board| {
+        let flipped = board.flipped();
+        let mirrored: Vec<Move> = board
+            .array_pseudo_legal_moves(board.turn)
+            .into_iter()
+            .map(|mv| Move {
+                from: mirror(mv.from),
+                to: mirror(mv.to),
+                promote: mv.promote,
+            })
+            .collect();
+        assert!(
+            same_moves(&mirrored, &flipped.array_pseudo_legal_moves(flipped.turn)),
+            "{}",
+            board.to_fen()
+        );
+    });
+}
+
+#[test]
+fn incremental_positional_matches_recompute() {
+    for_random_positions(350, 20, |board| {
+        let before = board.positional;
+        assert_eq!(before, board.compute_positional(), "{}", board.to_fen());
+        for mv in board.legal_moves() {
+            let undo = board.make_move(mv);
+            assert_eq!(board.positional, board.compute_positional(), "{}", mv);
+            board.unmake_move(mv, undo);
+            assert_eq!(board.positional, before, "{}", mv);
+        }
+    });
+}
+
+// A restarted server has to bring back the whole game, moves and clocks included,
+// and not just where it ended up
+#[test]
+fn restart_recovers_the_whole_game() {
+    let base = scratch_dir("recovery").join("game.pgn");
+    for game in 0..6 {
+        let mut rng = random::SplitMix64::new(397 + game);
+        let mut board = Board::new();
+        if game % 2 == 1 {
+            board
+                .set_time_control(Duration::from_secs(3600), Duration::from_secs(2))
+                .unwrap();
+        }
+        while board.status() == Status::Ongoing {
+            let mv = board.random_move(&mut rng).unwrap();
+            board.step(mv.from, mv.to, mv.promote).unwrap();
+        }
+        let games = Games::new(board);
+        autosave(&games, &base, &mut HashMap::new());
+        let (board, resumed) = (&games.games[&DEFAULT_GAME_ID].board, resume_games(&base));
+        let loaded = &resumed.games[&DEFAULT_GAME_ID].board;
+        assert_eq!(loaded.to_fen(), board.to_fen());
+        assert_eq!(loaded.san_history(), board.san_history());
+        assert_eq!(loaded.status(), board.status());
+        let clocks = |board: &Board| {
+            board.clock.map(|clock| {
+                let times = (format_clock(clock.white), format_clock(clock.black));
+                (clock.base, clock.increment, times)
+            })
+        };
+        assert_eq!(clocks(loaded), clocks(board));
+    }
+
+    // A save that can't be read back starts a new game instead of failing
+    fs::write(&base, "1. e4 e5 2. Qxf7 {truncated").unwrap();
+    let board = resume_game(&base);
+    assert_eq!(board.to_fen(), Board::new().to_fen());
+    assert!(board.san_history().is_empty());
+    fs::remove_dir_all(base.parent().unwrap()).unwrap();
+}
+
+// `chess fuzz` takes a fresh seed each run, this one stays put for CI
+#[test]
+fn random_games_pass_the_fuzzer() {
+    assert_eq!(fuzz(50, 349), Ok(()));
+}
+
+// An acceptor with no worker behind it, so only what it answers itself gets a
+// response
+fn start_acceptor() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        shutdown_token: None,
+        allowed_origins: None,
+    };
+    let (sender, _) = mpsc::channel();
+    std::thread::spawn(move || accept_requests(listener, config, sender));
+    addr
+}
+
+fn send_request(addr: SocketAddr, request: &[u8]) -> String {
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(READ_TIMEOUT / 2)).unwrap();
+    client.write_all(request).unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn a_silent_client_does_not_hold_up_others() {
+    let addr = start_acceptor();
+    let _silent = TcpStream::connect(addr).unwrap();
+    let response = send_request(addr, b"GET /healthz HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+}
+
+#[test]
+fn oversized_bodies_are_refused_unread() {
+    let head = format!(
+        "POST /load_pgn HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+        MAX_BODY_BYTES + 1
+    );
+    let response = send_request(start_acceptor(), head.as_bytes());
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+    assert!(
+        response.contains(r#""error":"payload_too_large""#),
+        "{}",
+        response
+    );
+}
+
+#[test]
+fn requests_queued_at_shutdown_are_answered() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let clients: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..clients.len() {
+        let (stream, _) = listener.accept().unwrap();
+        let request = parse_request("GET /game HTTP/1.1\r\n\r\n", Vec::new()).unwrap();
+        let start = Instant::now();
+        sender
+            .send(PendingRequest {
+                stream,
+                request,
+                start,
+            })
+            .unwrap();
+    }
+    let config = ServerConfig {
+        shutdown_token: None,
+        allowed_origins: None,
+    };
+    drain_on_shutdown(&receiver, &config);
+    for mut client in clients {
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+    }
+}
+
+#[test]
+fn legal_moves_from_caches_per_square_but_not_into_setup() {
+    let generated = || GENERATED_MOVES.with(|count| count.get());
+    let e2 = Location { x: 4, y: 1 };
+    let mut board = Board::new();
+    let before = generated();
+    assert_eq!(board.legal_moves_from(e2).len(), 2);
+    // Only the per-square cache can answer once the pseudo-legal moves are gone
+    *board.move_cache.borrow_mut() = [None, None];
+    assert_eq!(board.legal_moves_from(e2).len(), 2);
+    assert_eq!(generated() - before, 1);
+    board.begin_setup();
+    assert!(board.legal_moves_from(e2).is_empty());
+}
+
+#[test]
+fn probing_a_threat_describes_the_line_not_the_pass() {
+    let request = parse_request(
+        "GET /probe?moves=e2e4,e7e5&null=true HTTP/1.1\r\n\r\n",
+        Vec::new(),
+    )
+    .unwrap();
+    let mut response = Response {
+        cors: String::new(),
+        bytes: Vec::new(),
+    };
+    handle_request(&mut Board::new(), &request, None, &mut response);
+    let response = String::from_utf8(response.bytes).unwrap();
+    let body: serde_json::Value =
+        serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+    assert_eq!(
+        body["fen"],
+        "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq e6 0 2"
+    );
+    assert!(body["threat"]["move"].is_string(), "{}", body);
+}
+
+#[test]
+fn en_passant_and_promotions_are_ordered_with_captures() {
+    let board = Board::from_fen("4k3/1P6/8/3pP3/8/8/8/4K3 w - d6 0 2").unwrap();
+    let mut moves = board.all_legal_moves();
+    search::order_moves(&board, &mut moves, None);
+    let ordered: Vec<String> = moves.iter().map(|mv| mv.to_string()).collect();
+    assert_eq!(
+        ordered[..5],
+        ["b7b8q", "b7b8r", "b7b8b", "b7b8n", "e5d6"],
+        "{:?}",
+        ordered
+    );
+}
+
+#[test]
+fn each_level_is_at_least_as_strong_as_the_last() {
+    let levels: Vec<search::SearchParams> = (1..=10).map(search::params_for_level).collect();
+    for (level, pair) in (2..).zip(levels.windows(2)) {
+        let (below, this) = (&pair[0], &pair[1]);
+        assert!(this.depth >= below.depth, "level {}", level);
+        assert!(this.quiescence || !below.quiescence, "level {}", level);
+        assert!(this.noise <= below.noise, "level {}", level);
+        let same = (this.depth, this.quiescence, this.noise)
+            == (below.depth, below.quiescence, below.noise);
+        assert!(!same, "level {}", level);
+    }
+}